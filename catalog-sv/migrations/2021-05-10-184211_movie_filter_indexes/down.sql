@@ -0,0 +1,7 @@
+DROP INDEX movie_genre_names_idx;
+DROP INDEX movie_language_codes_idx;
+DROP INDEX movie_country_codes_idx;
+
+DROP FUNCTION movie_genre_names(genre[]);
+DROP FUNCTION movie_language_codes(language[]);
+DROP FUNCTION movie_country_codes(country[]);
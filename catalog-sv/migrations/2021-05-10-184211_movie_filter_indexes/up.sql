@@ -0,0 +1,16 @@
+-- immutable so they can be indexed, the filters query through the same functions
+CREATE FUNCTION movie_genre_names(genre[]) RETURNS TEXT[] AS $$
+    SELECT ARRAY(SELECT lower(g.label) FROM unnest($1) AS g)
+$$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+CREATE FUNCTION movie_language_codes(language[]) RETURNS TEXT[] AS $$
+    SELECT ARRAY(SELECT lower(l.code) FROM unnest($1) AS l)
+$$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+CREATE FUNCTION movie_country_codes(country[]) RETURNS TEXT[] AS $$
+    SELECT ARRAY(SELECT lower(c.code) FROM unnest($1) AS c)
+$$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+CREATE INDEX movie_genre_names_idx ON movies USING GIN (movie_genre_names(genres));
+CREATE INDEX movie_language_codes_idx ON movies USING GIN (movie_language_codes(spoken_languages));
+CREATE INDEX movie_country_codes_idx ON movies USING GIN (movie_country_codes(production_countries));
@@ -0,0 +1 @@
+-- nothing to undo, the index just has more fields
\ No newline at end of file
@@ -0,0 +1,2 @@
+UPDATE movies
+SET indexed = NULL;
\ No newline at end of file
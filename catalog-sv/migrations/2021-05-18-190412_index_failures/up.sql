@@ -0,0 +1,12 @@
+-- movies the index wouldn't take, retry_at is null once they've been given up on
+CREATE TABLE index_failures (
+    movie_id UUID PRIMARY KEY REFERENCES movies(id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    retry_at TIMESTAMPTZ NULL,
+    movie_updated TIMESTAMPTZ NOT NULL,
+    created TIMESTAMPTZ NOT NULL,
+    updated TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX index_failures_updated_idx ON index_failures(updated DESC, movie_id);
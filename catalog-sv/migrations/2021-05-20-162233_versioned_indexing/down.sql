@@ -0,0 +1 @@
+-- no undoing this bad boy
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- indexed is now the version of the movie that was indexed rather than when,
+-- anything that doesn't line up may have lost an edit so it's indexed again
+UPDATE movies
+SET indexed = NULL
+WHERE indexed IS DISTINCT FROM updated;
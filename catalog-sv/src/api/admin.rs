@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+
+use actix_web::{delete, get, HttpResponse, post, web};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::core::{action, IndexFailure};
+use crate::core::error::Error;
+use crate::db::{DbConnection, DbConnectionPool};
+use crate::dmn::reindexer::{Rebuild, ReindexDaemon};
+use crate::idx::IndexClient;
+
+#[derive(Serialize)]
+pub struct ReindexStatus {
+    /// The index reads and writes go to.
+    pub current: Option<String>,
+    pub rebuild: Option<Rebuild>,
+    /// Versioned indices nothing's using, left for an admin to delete.
+    pub stray: Vec<String>,
+}
+
+#[get("/admin/v1/reindex")]
+pub async fn get_reindex(
+    client: web::Data<IndexClient>,
+    reindexer: web::Data<Mutex<ReindexDaemon>>,
+) -> Result<HttpResponse, Error> {
+    let current = action::find_index_alias_target(&client).await?;
+    let rebuild = reindexer.lock().unwrap().status();
+    let stray = action::find_stray_indices(&client, rebuild.as_ref().map(|r| r.index.as_str())).await?;
+
+    Ok(HttpResponse::Ok().json(ReindexStatus { current, rebuild, stray }))
+}
+
+/// Starts rebuilding the index from the database in the background, the alias
+/// moves over to the new index once it's caught up.
+///
+/// The rebuild is claimed first, so a second request can't replace the index of one
+/// that's under way.
+#[post("/admin/v1/reindex")]
+pub async fn post_reindex(
+    client: web::Data<IndexClient>,
+    reindexer: web::Data<Mutex<ReindexDaemon>>,
+) -> Result<HttpResponse, Error> {
+    reindexer.lock().unwrap().reserve()?;
+
+    let index = match action::create_rebuild_index(&client).await {
+        Ok(index) => index,
+        Err(e) => {
+            reindexer.lock().unwrap().release();
+            return Err(e)
+        }
+    };
+
+    let rebuild = reindexer.lock().unwrap().begin(index)?;
+    let current = action::find_index_alias_target(&client).await?;
+    let stray = action::find_stray_indices(&client, Some(&rebuild.index)).await?;
+
+    Ok(HttpResponse::Accepted().json(ReindexStatus { current, rebuild: Some(rebuild), stray }))
+}
+
+/// Deletes a versioned index nothing's using, one of the `stray` in the reindex status.
+#[delete("/admin/v1/reindex/stray/{index}")]
+pub async fn delete_stray_index(
+    client: web::Data<IndexClient>,
+    reindexer: web::Data<Mutex<ReindexDaemon>>,
+    index: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let rebuilding = reindexer.lock().unwrap().status().map(|r| r.index);
+
+    if action::delete_stray_index(&client, &index, rebuilding.as_deref()).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+const MAX_INDEX_FAILURES: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct IndexFailuresQuery {
+    /// Only the movies that have been given up on, not the ones waiting on a retry.
+    #[serde(default)]
+    pub given_up: bool,
+    pub count: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct IndexFailuresResponse {
+    pub items: Vec<IndexFailure>,
+}
+
+#[derive(Serialize)]
+pub struct RetryResponse {
+    pub retried: Vec<Uuid>,
+}
+
+/// Movies that failed to index, most recent first.
+#[get("/admin/v1/index_failures")]
+pub async fn get_index_failures(
+    pool: web::Data<DbConnectionPool>,
+    query: web::Query<IndexFailuresQuery>,
+) -> Result<HttpResponse, Error> {
+    let q = query.into_inner();
+    let count = q.count.unwrap_or(100).clamp(1, MAX_INDEX_FAILURES);
+    let conn: DbConnection = pool.get()?;
+
+    let items = web::block(move || action::find_index_failures(&conn, q.given_up, count))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(IndexFailuresResponse { items }))
+}
+
+/// Puts every movie that's been given up on back in line for the indexer.
+#[post("/admin/v1/index_failures/retry")]
+pub async fn post_retry_index_failures(
+    pool: web::Data<DbConnectionPool>,
+) -> Result<HttpResponse, Error> {
+    let conn: DbConnection = pool.get()?;
+
+    let retried = web::block(move || action::retry_index_failures(&conn, None))
+        .await?;
+
+    Ok(HttpResponse::Accepted().json(RetryResponse { retried }))
+}
+
+/// Puts one failed movie back in line for the indexer, whether or not it's been given up on.
+#[post("/admin/v1/index_failures/{movie_id}/retry")]
+pub async fn post_retry_index_failure(
+    pool: web::Data<DbConnectionPool>,
+    movie_id: web::Path<Uuid>,
+) -> Result<HttpResponse, Error> {
+    let conn: DbConnection = pool.get()?;
+    let ids = vec![movie_id.into_inner()];
+
+    let retried = web::block(move || action::retry_index_failures(&conn, Some(ids)))
+        .await?;
+
+    if retried.is_empty() {
+        Ok(HttpResponse::NotFound().finish())
+    } else {
+        Ok(HttpResponse::Accepted().json(RetryResponse { retried }))
+    }
+}
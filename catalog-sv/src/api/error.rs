@@ -0,0 +1,111 @@
+use actix_web::{HttpRequest, HttpResponse, ResponseError, web};
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use log::{error, warn};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::core::error::Error;
+use crate::core::error::Error::{AnchorDecodeError, AnchorExpired, AnchorParseError, DBPoolError, DBQueryError, IndexQueryError, IndexQueryPartialError, InvalidPatch, PatchTestFailed, PreconditionFailed, QuerySyntaxError, ReindexConflict, ValidationError};
+
+/// How long clients should wait before retrying when no database connection was available.
+const RETRY_AFTER_SECONDS: u32 = 5;
+
+/// The body returned for any request that fails with a catalog `Error`.
+///
+/// `retryable` tells clients whether the same request may succeed later
+/// (the backing store or index was unavailable) or needs to be changed.
+#[derive(Serialize)]
+struct Problem {
+    code: &'static str,
+    message: String,
+    retryable: bool,
+    request_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    at: Option<SyntaxPosition>,
+}
+
+/// Where in a search string a syntax error is.
+#[derive(Serialize)]
+struct SyntaxPosition {
+    token: String,
+    offset: usize,
+}
+
+fn code(err: &Error) -> &'static str {
+    match err {
+        AnchorDecodeError(_) | AnchorParseError(_) => "invalid_anchor",
+        AnchorExpired => "anchor_expired",
+        ValidationError(_) => "invalid_request",
+        PreconditionFailed => "precondition_failed",
+        InvalidPatch(_) => "invalid_patch",
+        QuerySyntaxError { .. } => "invalid_query",
+        PatchTestFailed(_) => "patch_test_failed",
+        ReindexConflict(_) => "reindex_conflict",
+        IndexQueryError(_) | IndexQueryPartialError => "index_unavailable",
+        DBQueryError(_) => "database_error",
+        DBPoolError(_) => "database_unavailable",
+        _ => "internal_error",
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AnchorDecodeError(_) | AnchorParseError(_) | ValidationError(_) | InvalidPatch(_)
+            | QuerySyntaxError { .. } => StatusCode::BAD_REQUEST,
+            AnchorExpired => StatusCode::GONE,
+            PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            PatchTestFailed(_) | ReindexConflict(_) => StatusCode::CONFLICT,
+            IndexQueryError(_) | IndexQueryPartialError | DBPoolError(_) =>
+                StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let request_id = Uuid::new_v4();
+
+        // don't leak internals to clients, the request id ties the response to the logs
+        let message = if status.is_server_error() {
+            error!("request_id={} {}", request_id, self);
+            status.canonical_reason().unwrap_or("error").to_string()
+        } else {
+            warn!("request_id={} {}", request_id, self);
+            self.to_string()
+        };
+
+        let mut response = HttpResponse::build(status);
+        response.header("X-Request-Id", request_id.to_string());
+
+        if let DBPoolError(_) = self {
+            response.header(RETRY_AFTER, RETRY_AFTER_SECONDS.to_string());
+        }
+
+        response.json(Problem {
+            code: code(self),
+            message,
+            retryable: status == StatusCode::SERVICE_UNAVAILABLE,
+            request_id,
+            at: match self {
+                QuerySyntaxError { token, offset, .. } =>
+                    Some(SyntaxPosition { token: token.clone(), offset: *offset }),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// Query strings that can't be read get the same problem as any other invalid request.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|err: QueryPayloadError, _: &HttpRequest| ValidationError(err.to_string()).into())
+}
+
+/// Like `query_config` for request bodies.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|err: JsonPayloadError, _: &HttpRequest| ValidationError(err.to_string()).into())
+}
@@ -1,20 +1,33 @@
-use actix_web::{delete, Error, get, HttpResponse, post, put, Responder, web};
-use actix_web::error::BlockingError;
+use actix_web::{delete, get, HttpMessage, HttpResponse, patch, post, put, Responder, web};
+use actix_web::http::header::ETag;
 use actix_web::web::Json;
-use futures::TryFutureExt;
-use log::error;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::core::{CreateMovieParams, HasId, Movie, Page, PaginationParameters, UpdateMovieParams};
+use crate::core::{CreateMovieParams, Facets, Fuzziness, HasId, MatchMode, Movie, MovieFilter, MovieSort, Page, PaginationParameters, SearchMatch, SearchQuery, SearchResults, SearchSort, TitleSuggestion, UpdateMovieParams};
 use crate::core::action;
+use crate::core::error::Error;
+use crate::core::error::Error::ValidationError;
+use crate::core::patch::PatchOperation;
+use crate::core::query;
 use crate::db::DbConnection;
 use crate::db::DbConnectionPool;
-use crate::idx::IndexClient;
+use crate::idx::{IndexClient, SearchContexts};
 use std::collections::HashMap;
 use either::Either::{Left, Right};
 use either::Either;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+pub mod admin;
+mod error;
+mod version;
+
+pub use error::{json_config, query_config};
+
+const MAX_BATCH_SIZE: usize = 500;
+const MAX_SUGGESTIONS: i64 = 10;
+const MAX_RELATED: i64 = 50;
+const MERGE_PATCH: &str = "application/merge-patch+json";
+const JSON_PATCH: &str = "application/json-patch+json";
 
 #[get("/health")]
 pub async fn health() -> impl Responder {
@@ -26,79 +39,236 @@ pub async fn post_movie(
     pool: web::Data<DbConnectionPool>,
     req: Json<CreateMovieParams>,
 ) -> Result<HttpResponse, Error> {
-    let conn: DbConnection = pool.get()
-        .expect("couldn't get db connection from pool");
+    let conn: DbConnection = pool.get()?;
 
     let movie = web::block(move || action::create_movie(&conn, req.into_inner()))
-        .await
-        .map_err(|e| {
-            error!("{}", e);
-            HttpResponse::InternalServerError().finish()
-        })?;
+        .await?;
 
     match movie {
-        Left(id) => Ok(HttpResponse::Conflict().json(id)),
-        Right(m) => Ok(HttpResponse::Created().json(m))
+        Left(existing) => Ok(HttpResponse::Conflict()
+            .set(ETag(version::entity_tag(&existing.updated)))
+            .json(existing)),
+        Right(m) => Ok(HttpResponse::Created()
+            .set(ETag(version::entity_tag(&m.updated)))
+            .json(m))
+    }
+}
+
+/// The outcome for one movie of a batch create.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchCreateResult {
+    Created { movie: Box<Movie> },
+    Conflict { id: Uuid },
+    Invalid { message: String },
+}
+
+/// Movies that don't parse or validate don't stop the rest from being created,
+/// results are in the order the movies were given.
+#[post("/movies/v1/batch")]
+pub async fn post_movies(
+    pool: web::Data<DbConnectionPool>,
+    req: Json<Vec<serde_json::Value>>,
+) -> Result<HttpResponse, Error> {
+    let items = req.into_inner();
+    if items.len() > MAX_BATCH_SIZE {
+        return Err(ValidationError(format!("batches can have at most {} movies", MAX_BATCH_SIZE)))
     }
+
+    let parsed: Vec<Result<CreateMovieParams, String>> = items.into_iter()
+        .map(|item| serde_json::from_value::<CreateMovieParams>(item)
+            .map_err(|e| e.to_string())
+            .and_then(|p| p.validate().map(|_| p).map_err(|e| e.to_string())))
+        .collect();
+
+    let valid: Vec<CreateMovieParams> = parsed.iter()
+        .filter_map(|p| p.as_ref().ok().cloned())
+        .collect();
+
+    let conn: DbConnection = pool.get()?;
+
+    let mut created = web::block(move || action::create_movies(&conn, valid))
+        .await?
+        .into_iter();
+
+    let results: Vec<BatchCreateResult> = parsed.into_iter()
+        .map(|p| match p {
+            Err(message) => BatchCreateResult::Invalid { message },
+            Ok(_) => match created.next().expect("a result for every valid movie") {
+                Left(existing) => BatchCreateResult::Conflict { id: existing.id },
+                Right(movie) => BatchCreateResult::Created { movie: Box::new(movie) },
+            },
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Deserialize)]
+pub struct MultiGetRequest {
+    pub ids: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct MultiGetItem {
+    pub id: Uuid,
+    pub found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub movie: Option<Movie>,
+}
+
+#[derive(Serialize)]
+pub struct MultiGetResponse {
+    pub items: Vec<MultiGetItem>,
+}
+
+/// Items are in the order of the requested ids, movies that don't exist are marked not found.
+#[post("/movies/v1/_mget")]
+pub async fn mget_movies(
+    pool: web::Data<DbConnectionPool>,
+    req: Json<MultiGetRequest>,
+) -> Result<HttpResponse, Error> {
+    let ids = req.into_inner().ids;
+    if ids.len() > MAX_BATCH_SIZE {
+        return Err(ValidationError(format!("can get at most {} movies at once", MAX_BATCH_SIZE)))
+    }
+
+    let conn: DbConnection = pool.get()?;
+    let query_ids = ids.clone();
+
+    let found: HashMap<Uuid, Movie> = web::block(move || action::find_movies_with_ids(&conn, query_ids))
+        .await?
+        .items
+        .into_iter()
+        .map(|m| (m.id, m))
+        .collect();
+
+    let items = ids.into_iter()
+        .map(|id| {
+            // the same id can be asked for more than once
+            let movie = found.get(&id).cloned();
+            MultiGetItem { id, found: movie.is_some(), movie }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(MultiGetResponse { items }))
+}
+
+#[derive(Deserialize)]
+pub struct SuggestQuery {
+    pub prefix: String,
+    pub count: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SuggestResponse {
+    pub items: Vec<TitleSuggestion>,
+}
+
+/// Title autocomplete, has to be registered before `get_movie` so `_suggest` isn't taken for an id.
+#[get("/movies/v1/_suggest")]
+pub async fn suggest_movies(
+    client: web::Data<IndexClient>,
+    query: web::Query<SuggestQuery>,
+) -> Result<HttpResponse, Error> {
+    let q = query.into_inner();
+    let count = q.count.unwrap_or(5).clamp(1, MAX_SUGGESTIONS);
+
+    let items = action::suggest_titles(&client, &q.prefix, count).await?;
+
+    Ok(HttpResponse::Ok().json(SuggestResponse { items }))
 }
 
 #[get("/movies/v1/{movie_id}")]
 pub async fn get_movie(
+    req: web::HttpRequest,
     pool: web::Data<DbConnectionPool>,
     movie_id: web::Path<Uuid>,
 ) -> Result<HttpResponse, Error> {
-    let conn: DbConnection = pool.get()
-        .expect("couldn't get db connection from pool");
+    let conn: DbConnection = pool.get()?;
 
     let maybe = web::block(move || action::find_one_movie(&conn, movie_id.into_inner()))
-        .await
-        .map_err(|e| {
-            error!("{}", e);
-            HttpResponse::InternalServerError().finish()
-        })?;
+        .await?;
 
     match maybe {
         None => Ok(HttpResponse::NotFound().finish()),
-        Some(movie) => Ok(HttpResponse::Ok().json(movie))
+        Some(movie) => {
+            let tag = version::entity_tag(&movie.updated);
+            let last_modified = movie.updated;
+            Ok(version::conditional_ok(&req, tag, &last_modified, movie))
+        }
     }
 }
 
 #[put("/movies/v1/{movie_id}")]
 pub async fn put_movie(
+    http_req: web::HttpRequest,
     pool: web::Data<DbConnectionPool>,
     movie_id: web::Path<Uuid>,
     req: Json<UpdateMovieParams>,
 ) -> Result<HttpResponse, Error> {
-    let conn: DbConnection = pool.get()
-        .expect("couldn't get db connection from pool");
+    let expected_versions = version::if_match(&http_req)?;
+    let conn: DbConnection = pool.get()?;
 
-    let updated = web::block(move || action::update_movie(&conn, movie_id.into_inner(), req.into_inner()))
-        .await
-        .map_err(|e| {
-            error!("{}", e);
-            HttpResponse::InternalServerError().finish()
-        })?;
+    let updated = web::block(move ||
+        action::update_movie(&conn, movie_id.into_inner(), req.into_inner(), &expected_versions)
+    ).await?;
 
     match updated {
         None => Ok(HttpResponse::NotFound().finish()),
-        Some(movie) => Ok(HttpResponse::Ok().json(movie))
+        Some(movie) => Ok(HttpResponse::Ok()
+            .set(ETag(version::entity_tag(&movie.updated)))
+            .json(movie))
+    }
+}
+
+/// `application/merge-patch+json` (or plain json) bodies are merge patches,
+/// `application/json-patch+json` bodies are JSON Patch operations on the array fields.
+#[patch("/movies/v1/{movie_id}")]
+pub async fn patch_movie(
+    http_req: web::HttpRequest,
+    pool: web::Data<DbConnectionPool>,
+    movie_id: web::Path<Uuid>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let expected_versions = version::if_match(&http_req)?;
+    let id = movie_id.into_inner();
+
+    let invalid = |e: serde_json::Error| ValidationError(e.to_string());
+    let changes = match http_req.content_type() {
+        JSON_PATCH => Left(serde_json::from_slice::<Vec<PatchOperation>>(&body).map_err(invalid)?),
+        MERGE_PATCH | "application/json" =>
+            Right(serde_json::from_slice::<UpdateMovieParams>(&body).map_err(invalid)?),
+        _ => return Ok(HttpResponse::UnsupportedMediaType().finish()),
+    };
+
+    let conn: DbConnection = pool.get()?;
+
+    let patched = web::block(move || match changes {
+        Left(ops) => action::patch_movie(&conn, id, ops, &expected_versions),
+        Right(params) => action::update_movie(&conn, id, params, &expected_versions),
+    }).await?;
+
+    match patched {
+        None => Ok(HttpResponse::NotFound().finish()),
+        Some(movie) => Ok(HttpResponse::Ok()
+            .set(ETag(version::entity_tag(&movie.updated)))
+            .json(movie))
     }
 }
 
 #[delete("/movies/v1/{movie_id}")]
 pub async fn delete_movie(
+    http_req: web::HttpRequest,
     pool: web::Data<DbConnectionPool>,
     movie_id: web::Path<Uuid>,
 ) -> Result<HttpResponse, Error> {
-    let conn: DbConnection = pool.get()
-        .expect("couldn't get db connection from pool");
+    let expected_versions = version::if_match(&http_req)?;
+    let conn: DbConnection = pool.get()?;
 
-    let was_present = web::block(move || action::delete_movie(&conn, movie_id.into_inner()))
-        .await
-        .map_err(|e| {
-            error!("{}", e);
-            HttpResponse::InternalServerError().finish()
-        })?;
+    let was_present = web::block(move ||
+        action::delete_movie(&conn, movie_id.into_inner(), &expected_versions)
+    ).await?;
 
     if was_present {
         Ok(HttpResponse::NoContent().finish())
@@ -107,26 +277,59 @@ pub async fn delete_movie(
     }
 }
 
+#[post("/movies/v1/{movie_id}/restore")]
+pub async fn restore_movie(
+    pool: web::Data<DbConnectionPool>,
+    movie_id: web::Path<Uuid>,
+) -> Result<HttpResponse, Error> {
+    let conn: DbConnection = pool.get()?;
+
+    let restored = web::block(move || action::restore_movie(&conn, movie_id.into_inner()))
+        .await?;
+
+    match restored {
+        None => Ok(HttpResponse::NotFound().finish()),
+        Some(movie) => Ok(HttpResponse::Ok()
+            .set(ETag(version::entity_tag(&movie.updated)))
+            .json(movie))
+    }
+}
+
+/// A movie in a listing, wrapped with why it matched when that was asked for.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum QueryItem {
+    Plain(Movie),
+    Matched {
+        movie: Movie,
+        #[serde(rename = "match")]
+        matched: SearchMatch,
+    },
+}
+
 #[derive(Serialize)]
 pub struct QueryResponse {
-    pub items: Vec<Movie>,
+    pub items: Vec<QueryItem>,
     pub next_page: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facets: Option<Facets>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub did_you_mean: Option<String>,
 }
 
 impl QueryResponse {
+    /// The next page link repeats the request with the next anchor in place of its own.
     fn from_page(
         page: Page<Movie>,
-        count: Option<i64>,
-        search_term: Option<String>,
-        base_url: String,
+        mut matches: Option<HashMap<Uuid, SearchMatch>>,
+        req: &web::HttpRequest,
     ) -> QueryResponse {
-        let query_parts: Vec<String> = vec![
-            count.map(|c| format!("count={}", c)),
-            search_term
-                .map(|s| format!("search={}", utf8_percent_encode(&s, NON_ALPHANUMERIC))),
-            page.next_anchor.as_ref()
-                .map(|a| format!("anchor={}", a)),
-        ].into_iter().flatten().collect();
+        let query_parts: Vec<String> = req.query_string()
+            .split('&')
+            .filter(|part| !part.is_empty() && !part.starts_with("anchor="))
+            .map(|part| part.to_string())
+            .chain(page.next_anchor.as_ref().map(|a| format!("anchor={}", a)))
+            .collect();
 
         let q_string = if query_parts.is_empty() {
             "".to_string()
@@ -134,9 +337,50 @@ impl QueryResponse {
             format!("?{}", query_parts.join("&"))
         };
 
+        let items = page.items.into_iter()
+            .map(|movie| match matches.as_mut() {
+                None => QueryItem::Plain(movie),
+                Some(m) => QueryItem::Matched {
+                    matched: m.remove(&movie.id).unwrap_or_default(),
+                    movie,
+                },
+            })
+            .collect();
+
         QueryResponse {
-            items: page.items,
-            next_page: page.next_anchor.map(|_| format!("{}{}", base_url, q_string))
+            items,
+            next_page: page.next_anchor.map(|_| format!("{}{}", req.path(), q_string)),
+            facets: None,
+            did_you_mean: None,
+        }
+    }
+}
+
+/// Searches and listings share some sort names but not all, a shared name is
+/// read as a search sort and turned into a listing one when it's needed.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Sort {
+    Search(SearchSort),
+    List(MovieSort),
+}
+
+impl Sort {
+    fn for_search(self) -> Result<SearchSort, Error> {
+        match self {
+            Sort::Search(s) => Ok(s),
+            Sort::List(s) => Err(ValidationError(format!("can't sort searches by {}", s))),
+        }
+    }
+
+    fn for_list(self) -> Result<MovieSort, Error> {
+        match self {
+            Sort::List(s) => Ok(s),
+            Sort::Search(SearchSort::Title) => Ok(MovieSort::Title),
+            Sort::Search(SearchSort::ReleaseDate) => Ok(MovieSort::ReleaseDate),
+            Sort::Search(SearchSort::ReleaseDateDesc) => Ok(MovieSort::ReleaseDateDesc),
+            Sort::Search(SearchSort::Relevance) =>
+                Err(ValidationError("relevance is only available with search".to_string())),
         }
     }
 }
@@ -144,12 +388,37 @@ impl QueryResponse {
 #[derive(Clone, Deserialize)]
 pub struct Query {
     pub search: Option<String>,
+    pub sort: Option<Sort>,
+    #[serde(rename = "match")]
+    pub match_mode: Option<MatchMode>,
+    pub fuzziness: Option<Fuzziness>,
+    #[serde(default)]
+    pub facets: bool,
+    #[serde(default)]
+    pub highlight: bool,
+}
+
+impl Query {
+    /// The first option given that only means anything to a search.
+    fn search_only_option(&self) -> Option<&'static str> {
+        if self.match_mode.is_some() {
+            Some("match")
+        } else if self.fuzziness.is_some() {
+            Some("fuzziness")
+        } else if self.facets {
+            Some("facets")
+        } else if self.highlight {
+            Some("highlight")
+        } else {
+            None
+        }
+    }
 }
 
 fn backfill_unresolved_movies(
     conn: &DbConnection,
     found: Page<Either<HasId, Movie>>,
-) -> Result<Page<Movie>, crate::core::error::Error> {
+) -> Result<Page<Movie>, Error> {
     let unresolved_ids = found.items.iter()
         .filter_map(|e| match e {
             Left(id) => Some(id.id),
@@ -175,41 +444,105 @@ fn backfill_unresolved_movies(
     })
 }
 
+#[derive(Deserialize)]
+pub struct RelatedQuery {
+    pub count: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RelatedResponse {
+    pub items: Vec<Movie>,
+}
+
+/// Movies similar to this one, most similar first, found in the index and read from the database.
+#[get("/movies/v1/{movie_id}/related")]
+pub async fn get_related_movies(
+    pool: web::Data<DbConnectionPool>,
+    client: web::Data<IndexClient>,
+    movie_id: web::Path<Uuid>,
+    query: web::Query<RelatedQuery>,
+) -> Result<HttpResponse, Error> {
+    let id = movie_id.into_inner();
+    let count = query.count.unwrap_or(10).clamp(1, MAX_RELATED);
+
+    let conn: DbConnection = pool.get()?;
+    let found = web::block(move || action::find_one_movie(&conn, id))
+        .await?;
+
+    if found.is_none() {
+        return Ok(HttpResponse::NotFound().finish())
+    }
+
+    let related = action::find_related_movies(&client, id, count).await?;
+
+    let conn: DbConnection = pool.get()?;
+    let page = web::block(move || backfill_unresolved_movies(&conn, related))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(RelatedResponse { items: page.items }))
+}
+
 #[get("/movies/v1")]
 pub async fn get_movies(
     req: web::HttpRequest,
     pool: web::Data<DbConnectionPool>,
     client: web::Data<IndexClient>,
+    contexts: web::Data<SearchContexts>,
     query: web::Query<Query>,
+    filter: web::Query<MovieFilter>,
     pagination: web::Query<PaginationParameters>,
 ) -> Result<HttpResponse, Error> {
     let p = pagination.into_inner();
     let q = query.into_inner();
+    let filter = filter.into_inner();
 
     let count: i64 = p.count.unwrap_or_else(|| 25);
     let anchor = p.anchor.clone();
 
-    let conn: DbConnection = pool.get()
-        .expect("couldn't get db connection from pool");
+    if let (None, Some(option)) = (&q.search, q.search_only_option()) {
+        return Err(ValidationError(format!("{} is only available with search", option)))
+    }
+
+    let action = match q {
+        Query { search: Some(typed), sort, match_mode, fuzziness, facets, highlight } => {
+            let parsed = query::parse(&typed)?;
+            let search = SearchQuery {
+                term: parsed.text,
+                qualifiers: parsed.qualifiers,
+                mode: match_mode.unwrap_or_default(),
+                fuzziness,
+                sort: sort.map(Sort::for_search).transpose()?,
+                filter,
+                facets,
+                highlight,
+            };
 
-    let action = match &q {
-        Query { search: Some(search_term) } =>
-            action::search_movies(&client, search_term, count, &anchor)
-                .map_err(BlockingError::Error)
-                .await,
-        _ =>
-            web::block(move || action::find_movies(&conn, count, &anchor))
+            action::search_movies(&client, &contexts, &search, count, &anchor)
                 .await
-                .map(|found|
-                    Page {
+        }
+        Query { sort, .. } => {
+            let sort = sort.map(Sort::for_list).transpose()?;
+            let conn: DbConnection = pool.get()?;
+
+            web::block(move || action::find_movies(&conn, count, sort, &filter, &anchor))
+                .await
+                .map_err(Error::from)
+                .map(|found| SearchResults {
+                    page: Page {
                         page_number: found.page_number,
                         next_anchor: found.next_anchor,
                         items: found.items.into_iter().map(|m| Right(m)).collect(),
-                    }
-                )
+                    },
+                    facets: None,
+                    did_you_mean: None,
+                    matches: None,
+                })
+        }
     };
 
-    let next = match action? {
+    let SearchResults { page: found, facets, did_you_mean, matches } = action?;
+
+    let next = match found {
         all_found if all_found.items.iter().all(|e| e.is_right()) =>
             Ok(Page {
                 page_number: all_found.page_number,
@@ -219,20 +552,26 @@ pub async fn get_movies(
                     .collect()
             }),
         backfill => {
-            let conn = pool.get()
-                .expect("couldn't get db connection from pool");
+            let conn = pool.get()?;
 
             web::block(move || backfill_unresolved_movies(&conn, backfill))
                 .await
+                .map_err(Error::from)
         }
     };
 
-    let movies = next
-        .map(|r| QueryResponse::from_page(r, p.count, q.search, req.path().to_string()))
-        .map_err(|e| {
-            error!("{}", e);
-            HttpResponse::InternalServerError().finish()
-        })?;
+    let page = next?;
+    let validators = version::page_validators(&page.items);
+    let movies = QueryResponse {
+        facets,
+        did_you_mean,
+        ..QueryResponse::from_page(page, matches, &req)
+    };
 
-    Ok(HttpResponse::Ok().json(movies))
+    match validators {
+        // facet counts cover more than the page, so the page's validators don't cover them
+        Some((tag, last_modified)) if movies.facets.is_none() =>
+            Ok(version::conditional_ok(&req, tag, &last_modified, movies)),
+        _ => Ok(HttpResponse::Ok().json(movies)),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,102 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::SystemTime;
+
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::header::{ETag, EntityTag, Header, HttpDate, IfMatch, IfModifiedSince, IfNoneMatch, LastModified};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::core::Movie;
+use crate::core::error::Error;
+use crate::core::error::Error::ValidationError;
+
+/// Every write bumps `Movie.updated`, so it doubles as the movie's version.
+pub fn entity_tag(updated: &DateTime<Utc>) -> EntityTag {
+    EntityTag::strong(updated.timestamp_nanos().to_string())
+}
+
+/// Validators for a page of movies, `None` for an empty page.
+///
+/// The newest `updated` on the page catches edits, the ids catch movies
+/// leaving or joining the page without any of the others changing.
+pub fn page_validators(movies: &[Movie]) -> Option<(EntityTag, DateTime<Utc>)> {
+    let last_modified = movies.iter().map(|m| m.updated).max()?;
+
+    let mut hasher = DefaultHasher::new();
+    movies.iter().for_each(|m| m.id.hash(&mut hasher));
+
+    let tag = EntityTag::weak(format!("{}-{:x}", last_modified.timestamp_nanos(), hasher.finish()));
+    Some((tag, last_modified))
+}
+
+fn http_date(at: &DateTime<Utc>) -> HttpDate {
+    HttpDate::from(SystemTime::from(*at))
+}
+
+/// Whether the client's copy is still current, by `If-None-Match` if it sent one,
+/// otherwise by `If-Modified-Since`.
+fn not_modified(req: &HttpRequest, tag: &EntityTag, last_modified: &DateTime<Utc>) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) if !tags.is_empty() => tags.iter().any(|t| t.weak_eq(tag)),
+        _ => match IfModifiedSince::parse(req) {
+            // http dates only have second precision
+            Ok(IfModifiedSince(since)) =>
+                last_modified.timestamp() <= DateTime::<Utc>::from(SystemTime::from(since)).timestamp(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// A `200 OK` carrying `body` and its validators, or a bare `304 Not Modified`
+/// when the client already has it.
+pub fn conditional_ok<T: Serialize>(
+    req: &HttpRequest,
+    tag: EntityTag,
+    last_modified: &DateTime<Utc>,
+    body: T,
+) -> HttpResponse {
+    let fresh = not_modified(req, &tag, last_modified);
+
+    let mut response = if fresh {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+
+    response
+        .set(ETag(tag))
+        .set(LastModified(http_date(last_modified)));
+
+    if fresh {
+        response.finish()
+    } else {
+        response.json(body)
+    }
+}
+
+fn version(tag: &EntityTag) -> Option<DateTime<Utc>> {
+    if tag.weak {
+        // If-Match only ever uses the strong comparison
+        return None
+    }
+
+    tag.tag().parse::<i64>()
+        .ok()
+        .map(|nanos| Utc.timestamp_nanos(nanos))
+}
+
+/// The versions a write is conditional on, `None` when it's unconditional.
+///
+/// `If-Match: *` only requires the movie to exist, which every write already does.
+pub fn if_match(req: &HttpRequest) -> Result<Option<Vec<DateTime<Utc>>>, Error> {
+    let header = IfMatch::parse(req)
+        .map_err(|_| ValidationError("invalid If-Match header".to_string()))?;
+
+    match header {
+        IfMatch::Any => Ok(None),
+        IfMatch::Items(tags) if tags.is_empty() => Ok(None),
+        IfMatch::Items(tags) => Ok(Some(tags.iter().filter_map(version).collect())),
+    }
+}
@@ -1,36 +1,140 @@
-use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info, warn};
 use uuid::Uuid;
 
-use crate::core::{CreateMovieParams, DeleteMovie, IndexMovie, Movie, Page, UpdateMovieParams, HasId};
+use crate::core::{BulkIndexed, CreateMovieParams, DeleteMovie, HasId, IndexFailure, IndexRejection, Movie, MovieFilter, MovieSort, Page, RestoreMovie, SearchQuery, SearchResults, TitleSuggestion, UpdateMovieParams};
 use crate::core::error::Error;
+use crate::core::error::Error::{IndexMappingError, PreconditionFailed, ReindexConflict, ValidationError};
+use crate::core::patch;
+use crate::core::patch::PatchOperation;
 use crate::db;
 use crate::db::DbConnection;
 use crate::idx;
-use crate::idx::IndexClient;
+use crate::idx::{IndexClient, IndexState, MappingDrift, SearchContexts};
 use either::Either;
+use either::Either::Left;
 
-pub fn create_movie(conn: &DbConnection, movie: CreateMovieParams) -> Result<Either<HasId, Movie>, Error> {
+pub fn create_movie(conn: &DbConnection, movie: CreateMovieParams) -> Result<Either<Movie, Movie>, Error> {
     info!("creating movie {:?}", movie);
+    movie.validate()?;
     db::create_movie(conn, movie.create())
 }
 
-pub fn update_movie(conn: &DbConnection, id: Uuid, movie: UpdateMovieParams) -> Result<Option<Movie>, Error> {
-    info!("updating movie id={} {:?}", id, movie);
-    db::update_movie(conn, id, movie.update())
+/// Creates many (already validated) movies at once, results are in the order given.
+///
+/// A movie given more than once conflicts with the first time it was given.
+pub fn create_movies(conn: &DbConnection, params: Vec<CreateMovieParams>) -> Result<Vec<Either<Movie, Movie>>, Error> {
+    info!("creating movies count={}", params.len());
+    let new_movies: Vec<Movie> = params.iter().map(|p| p.create()).collect();
+
+    let mut seen = HashSet::new();
+    let unique: Vec<Movie> = new_movies.iter()
+        .filter(|m| seen.insert(m.id))
+        .cloned()
+        .collect();
+
+    let created: HashMap<Uuid, Either<Movie, Movie>> = db::create_movies(conn, &unique)?
+        .into_iter()
+        .map(|e| (e.as_ref().either(|m| m.id, |m| m.id), e))
+        .collect();
+
+    let mut seen = HashSet::new();
+    Ok(new_movies.iter()
+        .map(|m| {
+            let result = created[&m.id].clone();
+            if seen.insert(m.id) {
+                result
+            } else {
+                Left(result.into_inner())
+            }
+        })
+        .collect())
+}
+
+/// Tells a conditional write that matched nothing because the movie has moved on
+/// apart from one that matched nothing because the movie doesn't exist.
+fn unless_stale(
+    conn: &DbConnection,
+    id: Uuid,
+    expected_versions: &Option<Vec<DateTime<Utc>>>,
+    written: Option<Movie>,
+) -> Result<Option<Movie>, Error> {
+    match (written, expected_versions) {
+        (None, Some(_)) if db::find_one_movie(conn, id)?.is_some() => Err(PreconditionFailed),
+        (written, _) => Ok(written),
+    }
+}
+
+pub fn update_movie(
+    conn: &DbConnection,
+    id: Uuid,
+    movie: UpdateMovieParams,
+    expected_versions: &Option<Vec<DateTime<Utc>>>,
+) -> Result<Option<Movie>, Error> {
+    info!("updating movie id={} {:?} if_match={:?}", id, movie, expected_versions);
+    let updated = db::update_movie(conn, id, movie.update(), expected_versions)?;
+    unless_stale(conn, id, expected_versions, updated)
 }
 
-pub fn delete_movie(conn: &DbConnection, id: Uuid) -> Result<bool, Error> {
-    debug!("deleting movie id={}", id);
-    let soft_deleted = db::update_movie(conn, id, DeleteMovie.update())
+/// Applies JSON Patch operations to the movie as it is now, writing the result only
+/// over that same version so a concurrent write can't be silently undone.
+pub fn patch_movie(
+    conn: &DbConnection,
+    id: Uuid,
+    ops: Vec<PatchOperation>,
+    expected_versions: &Option<Vec<DateTime<Utc>>>,
+) -> Result<Option<Movie>, Error> {
+    info!("patching movie id={} {:?} if_match={:?}", id, ops, expected_versions);
+    let current = match db::find_one_movie(conn, id)? {
+        None => return Ok(None),
+        Some(m) => m,
+    };
+
+    if let Some(versions) = expected_versions {
+        if !versions.contains(&current.updated) {
+            return Err(PreconditionFailed)
+        }
+    }
+
+    let params = patch::apply(&current, ops)?;
+    let read_version = Some(vec![current.updated]);
+
+    let updated = db::update_movie(conn, id, params.update(), &read_version)?;
+    unless_stale(conn, id, &read_version, updated)
+}
+
+pub fn delete_movie(
+    conn: &DbConnection,
+    id: Uuid,
+    expected_versions: &Option<Vec<DateTime<Utc>>>,
+) -> Result<bool, Error> {
+    debug!("deleting movie id={} if_match={:?}", id, expected_versions);
+    let deleted = db::update_movie(conn, id, DeleteMovie.update(), expected_versions)?;
+    let soft_deleted = unless_stale(conn, id, expected_versions, deleted)
         .map(|opt| opt.is_some())?;
 
     info!("soft deleted movie id={}", id);
     Ok(soft_deleted)
 }
 
-pub fn delete_soft_deleted(conn: &DbConnection) -> Result<usize, Error> {
-    debug!("deleting movies that have been soft deleted");
-    let deleted = db::delete_soft_deleted(conn)?;
+/// Brings back a soft deleted movie that hasn't been purged yet, restoring one
+/// that isn't deleted leaves it as it is.
+pub fn restore_movie(conn: &DbConnection, id: Uuid) -> Result<Option<Movie>, Error> {
+    debug!("restoring movie id={}", id);
+    match db::restore_movie(conn, id, RestoreMovie.update())? {
+        Some(restored) => {
+            info!("restored movie id={}", id);
+            Ok(Some(restored))
+        }
+        None => db::find_one_movie(conn, id),
+    }
+}
+
+pub fn delete_soft_deleted(conn: &DbConnection, retention: Duration) -> Result<usize, Error> {
+    debug!("deleting movies that have been soft deleted for longer than {}", retention);
+    let deleted = db::delete_soft_deleted(conn, Utc::now() - retention)?;
     if deleted > 0 {
         info!("deleted {} movies", deleted);
     }
@@ -43,9 +147,16 @@ pub fn find_one_movie(conn: &DbConnection, id: Uuid) -> Result<Option<Movie>, Er
     db::find_one_movie(conn, id)
 }
 
-pub fn find_movies(conn: &DbConnection, count: i64, anchor: &Option<String>) -> Result<Page<Movie>, Error> {
-    info!("finding movies count={:?} anchor={:?}", count, anchor);
-    db::find_movies(conn, count, anchor)
+pub fn find_movies(
+    conn: &DbConnection,
+    count: i64,
+    sort: Option<MovieSort>,
+    filter: &MovieFilter,
+    anchor: &Option<String>,
+) -> Result<Page<Movie>, Error> {
+    info!("finding movies count={:?} sort={:?} filter={:?} anchor={:?}", count, sort, filter, anchor);
+    filter.validate()?;
+    db::find_movies(conn, count, sort, filter, anchor)
 }
 
 pub fn find_movies_with_ids(conn: &DbConnection, ids: Vec<Uuid>) -> Result<Page<Movie>, Error> {
@@ -63,17 +174,53 @@ pub fn find_movies_with_ids(conn: &DbConnection, ids: Vec<Uuid>) -> Result<Page<
 
 pub async fn search_movies(
     client: &IndexClient,
-    search_term: &String,
+    contexts: &SearchContexts,
+    search: &SearchQuery,
     count: i64,
     anchor: &Option<String>
-) -> Result<Page<Either<HasId, Movie>>, Error> {
-    info!("searching movies search_term={} count={:?} anchor={:?}", search_term, count, anchor);
-    idx::search_movies(client, search_term, count, anchor).await
+) -> Result<SearchResults, Error> {
+    info!("searching movies search={:?} count={:?} anchor={:?}", search, count, anchor);
+    search.filter.validate()?;
+    idx::search_movies(client, contexts, search, count, anchor).await
+}
+
+pub async fn find_related_movies(client: &IndexClient, id: Uuid, count: i64) -> Result<Page<Either<HasId, Movie>>, Error> {
+    info!("finding related movies id={} count={:?}", id, count);
+    idx::find_related_movies(client, id, count).await
+}
+
+pub async fn suggest_titles(client: &IndexClient, prefix: &str, count: i64) -> Result<Vec<TitleSuggestion>, Error> {
+    debug!("suggesting titles prefix={} count={:?}", prefix, count);
+
+    if prefix.trim().is_empty() {
+        return Err(ValidationError("prefix can't be blank".to_string()))
+    }
+
+    idx::suggest_titles(client, prefix, count).await
 }
 
-pub async fn create_index(client: &IndexClient) -> Result<bool, Error> {
+/// Makes sure there's an index and deals with one that isn't what's expected as `on_drift`
+/// says, giving the index to rebuild into when a rebuild is needed.
+pub async fn create_index(client: &IndexClient, on_drift: MappingDrift) -> Result<Option<String>, Error> {
     info!("creating catalog index");
-    idx::create_index(client).await
+
+    let problem = match idx::create_index(client).await? {
+        IndexState::Current => return Ok(None),
+        IndexState::Outdated(index) => {
+            warn!("index is outdated index={} expected={}", index, idx::versioned_index_name());
+            format!("{} is outdated", index)
+        }
+        IndexState::Drifted(index, drift) => {
+            warn!("index mapping has drifted index={} differences={}", index, serde_json::to_string(&drift)?);
+            format!("{} has drifted at {}", index, drift.iter().map(|d| d.path.as_str()).collect::<Vec<_>>().join(", "))
+        }
+    };
+
+    match on_drift {
+        MappingDrift::Log => Ok(None),
+        MappingDrift::Fail => Err(IndexMappingError(problem)),
+        MappingDrift::Migrate => create_rebuild_index(client).await.map(Some),
+    }
 }
 
 pub fn find_movies_to_index(conn: &DbConnection, count: i64) -> Result<Vec<Movie>, Error> {
@@ -85,16 +232,198 @@ pub fn find_movies_to_index(conn: &DbConnection, count: i64) -> Result<Vec<Movie
     Ok(stale)
 }
 
-pub async fn index_movies(client: &IndexClient, movies: Vec<Movie>) -> Result<Vec<Movie>, Error> {
+pub async fn index_movies(client: &IndexClient, movies: Vec<Movie>) -> Result<BulkIndexed, Error> {
     info!("adding movies to catalog index {:?}", movies);
     idx::index_movies(client, movies).await
 }
 
+/// Only movies that haven't changed since they were read are marked, anything edited
+/// while it was being indexed stays stale and gets indexed again.
 pub fn mark_movies_indexed(conn: &DbConnection, movies: Vec<Movie>) -> Result<Vec<Movie>, Error> {
     debug!("marking movies indexed {:?}", movies);
-    db::update_movies(
-        conn,
-        movies.iter().map(|m|m.id).collect(),
-        IndexMovie.update()
-    )
-}
\ No newline at end of file
+    let marked = db::mark_indexed(conn, &movies)?;
+    if marked.len() < movies.len() {
+        info!("movies changed while indexing, leaving for next time count={:?}", movies.len() - marked.len())
+    }
+    Ok(marked)
+}
+
+/// Marks what went into the index as indexed and puts off or gives up on what didn't.
+pub fn record_index_results(conn: &DbConnection, results: BulkIndexed) -> Result<Vec<Movie>, Error> {
+    db::transaction(conn, || {
+        let ids: Vec<Uuid> = results.indexed.iter().map(|m| m.id).collect();
+        db::delete_index_failures(conn, &ids)?;
+        record_index_failures(conn, results.rejected, true)?;
+        mark_movies_indexed(conn, results.indexed)
+    })
+}
+
+const INDEX_RETRY_FIRST_SECONDS: i64 = 30;
+const INDEX_RETRY_MOST_SECONDS: i64 = 60 * 60;
+const INDEX_ATTEMPTS: i32 = 8;
+
+fn next_index_failure(previous: Option<&IndexFailure>, rejection: IndexRejection, retry: bool, now: DateTime<Utc>) -> IndexFailure {
+    // a movie that's been edited since it last failed starts counting again
+    let attempts = match previous {
+        Some(p) if p.movie_updated == rejection.movie.updated => p.attempts + 1,
+        _ => 1,
+    };
+
+    let retry_at = if retry && rejection.retryable && attempts < INDEX_ATTEMPTS {
+        let delay = (INDEX_RETRY_FIRST_SECONDS << (attempts - 1)).min(INDEX_RETRY_MOST_SECONDS);
+        Some(now + Duration::seconds(delay))
+    } else {
+        None
+    };
+
+    IndexFailure {
+        movie_id: rejection.movie.id,
+        reason: rejection.reason,
+        attempts,
+        retry_at,
+        movie_updated: rejection.movie.updated,
+        created: previous.map(|p| p.created).unwrap_or(now),
+        updated: now,
+    }
+}
+
+/// Schedules a retry with exponential backoff for movies the index may take later,
+/// anything else, or anything that's run out of attempts, is given up on.
+pub fn record_index_failures(conn: &DbConnection, rejected: Vec<IndexRejection>, retry: bool) -> Result<Vec<IndexFailure>, Error> {
+    if rejected.is_empty() {
+        return Ok(Vec::new())
+    }
+
+    db::transaction(conn, || {
+        let ids: Vec<Uuid> = rejected.iter().map(|r| r.movie.id).collect();
+        let previous: HashMap<Uuid, IndexFailure> = db::find_index_failures_for(conn, &ids)?
+            .into_iter()
+            .map(|f| (f.movie_id, f))
+            .collect();
+
+        let now = Utc::now();
+        let failures: Vec<IndexFailure> = rejected.into_iter()
+            .map(|r| {
+                let p = previous.get(&r.movie.id);
+                next_index_failure(p, r, retry, now)
+            })
+            .collect();
+
+        for f in &failures {
+            match f.retry_at {
+                Some(at) => info!("movie failed to index, retrying at {} {:?}", at, f),
+                None => warn!("movie failed to index, giving up {:?}", f),
+            }
+        }
+
+        db::save_index_failures(conn, &failures)
+    })
+}
+
+pub fn find_index_failures(conn: &DbConnection, given_up: bool, count: i64) -> Result<Vec<IndexFailure>, Error> {
+    debug!("finding index failures given_up={:?} count={:?}", given_up, count);
+    db::find_index_failures(conn, given_up, count)
+}
+
+/// Forgets the failures and puts the movies back in line for the indexer, every
+/// movie that's been given up on when there are no ids.
+pub fn retry_index_failures(conn: &DbConnection, movie_ids: Option<Vec<Uuid>>) -> Result<Vec<Uuid>, Error> {
+    db::transaction(conn, || {
+        let ids = match movie_ids {
+            Some(ids) => db::find_index_failures_for(conn, &ids)?,
+            None => db::find_index_failures(conn, true, i64::MAX)?,
+        };
+        let ids: Vec<Uuid> = ids.into_iter().map(|f| f.movie_id).collect();
+
+        info!("retrying index failures {:?}", ids);
+        db::delete_index_failures(conn, &ids)?;
+        db::clear_indexed(conn, &ids)?;
+        Ok(ids)
+    })
+}
+
+pub fn find_movies_to_copy(conn: &DbConnection, after: Option<Uuid>, count: i64) -> Result<Vec<Movie>, Error> {
+    debug!("finding movies to copy after={:?} count={:?}", after, count);
+    db::find_movies_after_id(conn, after, count)
+}
+
+pub fn find_movies_changed_since(
+    conn: &DbConnection,
+    since: DateTime<Utc>,
+    after: Option<Uuid>,
+    count: i64,
+) -> Result<Vec<Movie>, Error> {
+    debug!("finding movies changed since={:?} after={:?} count={:?}", since, after, count);
+    db::find_movies_changed_since(conn, since, after, count)
+}
+
+pub async fn index_movies_into(client: &IndexClient, index: &str, movies: Vec<Movie>) -> Result<BulkIndexed, Error> {
+    info!("adding movies to index={} count={:?}", index, movies.len());
+    idx::index_movies_into(client, index, movies).await
+}
+
+pub async fn find_index_alias_target(client: &IndexClient) -> Result<Option<String>, Error> {
+    idx::alias_target(client).await
+}
+
+/// A fresh index of the current version to rebuild into, off to the side of the alias.
+///
+/// Rebuilding the current version, or into a name that's already taken, gets a name of
+/// its own. Nothing that's already there is touched, see `find_stray_indices`.
+pub async fn create_rebuild_index(client: &IndexClient) -> Result<String, Error> {
+    let target = idx::alias_target(client).await?;
+    let versioned = idx::versioned_index_name();
+
+    let taken = match &target {
+        Some(t) if idx::is_current_version(t) => true,
+        _ => idx::index_exists(client, &versioned).await?,
+    };
+    let index = if taken {
+        format!("{}_{}", versioned, Utc::now().format("%Y%m%d%H%M%S"))
+    } else {
+        versioned
+    };
+
+    info!("creating rebuild index={}", index);
+    idx::create_versioned_index(client, &index, false).await?;
+    Ok(index)
+}
+
+/// Versioned indices the alias isn't on and that aren't being rebuilt into here.
+///
+/// Any of them could be a rebuild that never finished, one another instance is still
+/// going with, or an index the alias was swapped away from, so there's no telling
+/// which are safe to throw away and they're only ever deleted by `delete_stray_index`.
+pub async fn find_stray_indices(client: &IndexClient, rebuilding: Option<&str>) -> Result<Vec<String>, Error> {
+    let target = idx::alias_target(client).await?;
+
+    let mut stray: Vec<String> = idx::versioned_indices(client).await?
+        .into_iter()
+        .filter(|i| target.as_deref() != Some(i.as_str()) && rebuilding != Some(i.as_str()))
+        .collect();
+    stray.sort();
+    Ok(stray)
+}
+
+/// Deletes one of the `find_stray_indices`, `false` if there's no such index.
+pub async fn delete_stray_index(client: &IndexClient, index: &str, rebuilding: Option<&str>) -> Result<bool, Error> {
+    if rebuilding == Some(index) {
+        return Err(ReindexConflict(format!("{} is being rebuilt into", index)))
+    }
+    if idx::alias_target(client).await?.as_deref() == Some(index) {
+        return Err(ReindexConflict(format!("{} is behind the alias", index)))
+    }
+    if !find_stray_indices(client, rebuilding).await?.iter().any(|i| i == index) {
+        return Ok(false)
+    }
+
+    info!("deleting stray index={}", index);
+    idx::delete_index(client, index).await?;
+    Ok(true)
+}
+
+pub async fn swap_index_alias(client: &IndexClient, index: &str) -> Result<(), Error> {
+    let previous = idx::swap_alias(client, index).await?;
+    info!("swapped index alias to={} from={:?}, the old index can be deleted once it's not needed", index, previous);
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use actix_web::error::BlockingError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -8,14 +9,43 @@ pub enum Error {
     DateParseError(#[from] chrono::ParseError),
     #[error("error querying database: {0}")]
     DBQueryError(#[from] diesel::result::Error),
+    #[error("error getting database connection: {0}")]
+    DBPoolError(#[from] r2d2::Error),
     #[error("error decoding anchor: {0}")]
     AnchorDecodeError(#[from] base64::DecodeError),
     #[error("error parsing anchor: {0}")]
     AnchorParseError(#[from] rmp_serde::decode::Error),
+    #[error("anchor has expired, start again from the first page")]
+    AnchorExpired,
     #[error("error querying index: {0}")]
     IndexQueryError(#[from] elasticsearch::Error),
     #[error("error with part of query index")]
     IndexQueryPartialError,
+    #[error("index mapping doesn't match: {0}")]
+    IndexMappingError(String),
+    #[error("can't rebuild the index: {0}")]
+    ReindexConflict(String),
     #[error("error serializing/deserializing json: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
-}
\ No newline at end of file
+    #[error("blocking operation was canceled")]
+    BlockingCanceled,
+    #[error("invalid request: {0}")]
+    ValidationError(String),
+    #[error("movie has changed since the version the request was conditional on")]
+    PreconditionFailed,
+    #[error("invalid patch: {0}")]
+    InvalidPatch(String),
+    #[error("patch test failed at {0}")]
+    PatchTestFailed(String),
+    #[error("invalid search at {offset} `{token}`: {message}")]
+    QuerySyntaxError { message: String, token: String, offset: usize },
+}
+
+impl From<BlockingError<Error>> for Error {
+    fn from(err: BlockingError<Error>) -> Self {
+        match err {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => Error::BlockingCanceled,
+        }
+    }
+}
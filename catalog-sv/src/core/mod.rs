@@ -1,12 +1,22 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
 use chrono::{DateTime, NaiveDate, Utc};
 use diesel::{AsChangeset, AsExpression, FromSqlRow, Identifiable, Insertable, Queryable};
-use serde::{Deserialize, Serialize};
+use either::Either;
+use serde::{Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
 
-use crate::db::schema::movies;
+use crate::core::error::Error;
+use crate::core::error::Error::ValidationError;
+use crate::core::query::Qualifier;
+use crate::db::schema::{index_failures, movies};
 
 pub mod action;
 pub mod error;
+pub mod patch;
+pub mod query;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct HasId {
@@ -19,6 +29,263 @@ pub struct PaginationParameters {
     pub anchor: Option<String>,
 }
 
+/// Orders movies can be listed in, the `-` prefixed ones are descending.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Default, Debug)]
+pub enum MovieSort {
+    #[default]
+    #[serde(rename = "title")]
+    Title,
+    #[serde(rename = "-title")]
+    TitleDesc,
+    #[serde(rename = "release_date")]
+    ReleaseDate,
+    #[serde(rename = "-release_date")]
+    ReleaseDateDesc,
+    #[serde(rename = "created")]
+    Created,
+    #[serde(rename = "-created")]
+    CreatedDesc,
+    #[serde(rename = "updated")]
+    Updated,
+    #[serde(rename = "-updated")]
+    UpdatedDesc,
+}
+
+impl MovieSort {
+    /// The name it's given in requests.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MovieSort::Title => "title",
+            MovieSort::TitleDesc => "-title",
+            MovieSort::ReleaseDate => "release_date",
+            MovieSort::ReleaseDateDesc => "-release_date",
+            MovieSort::Created => "created",
+            MovieSort::CreatedDesc => "-created",
+            MovieSort::Updated => "updated",
+            MovieSort::UpdatedDesc => "-updated",
+        }
+    }
+}
+
+impl fmt::Display for MovieSort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Orders search results can be in, `relevance` puts the best matches first.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Default, Debug)]
+pub enum SearchSort {
+    #[default]
+    #[serde(rename = "relevance")]
+    Relevance,
+    #[serde(rename = "title")]
+    Title,
+    #[serde(rename = "release_date")]
+    ReleaseDate,
+    #[serde(rename = "-release_date")]
+    ReleaseDateDesc,
+}
+
+impl SearchSort {
+    /// The name it's given in requests.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SearchSort::Relevance => "relevance",
+            SearchSort::Title => "title",
+            SearchSort::ReleaseDate => "release_date",
+            SearchSort::ReleaseDateDesc => "-release_date",
+        }
+    }
+}
+
+impl fmt::Display for SearchSort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Narrows a movie listing, values in a comma separated list match any of them,
+/// and every filter given has to match.
+///
+/// Release bounds are inclusive and take a year or a full date, so a decade facet
+/// is selected with `released_after=1980&released_before=1989`.
+#[derive(Clone, Deserialize, Default, Debug)]
+pub struct MovieFilter {
+    pub genre: Option<String>,
+    pub language: Option<String>,
+    pub country: Option<String>,
+    #[serde(default, deserialize_with = "first_day")]
+    pub released_after: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "last_day")]
+    pub released_before: Option<NaiveDate>,
+}
+
+fn release_bound<'de, D>(de: D, year_to_date: fn(i32) -> Option<NaiveDate>) -> Result<Option<NaiveDate>, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let raw = String::deserialize(de)?;
+    let parsed = match raw.parse::<i32>() {
+        Ok(year) => year_to_date(year),
+        Err(_) => NaiveDate::parse_from_str(&raw, "%Y-%m-%d").ok(),
+    };
+
+    parsed
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("{} is not a year or a yyyy-mm-dd date", raw)))
+}
+
+fn first_day<'de, D: Deserializer<'de>>(de: D) -> Result<Option<NaiveDate>, D::Error> {
+    release_bound(de, |year| NaiveDate::from_ymd_opt(year, 1, 1))
+}
+
+fn last_day<'de, D: Deserializer<'de>>(de: D) -> Result<Option<NaiveDate>, D::Error> {
+    release_bound(de, |year| NaiveDate::from_ymd_opt(year, 12, 31))
+}
+
+fn filter_values(raw: &Option<String>) -> Vec<String> {
+    raw.iter()
+        .flat_map(|r| r.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+impl MovieFilter {
+    pub fn genres(&self) -> Vec<String> {
+        filter_values(&self.genre)
+    }
+
+    pub fn languages(&self) -> Vec<String> {
+        filter_values(&self.language)
+    }
+
+    pub fn countries(&self) -> Vec<String> {
+        filter_values(&self.country)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        match (self.released_after, self.released_before) {
+            (Some(after), Some(before)) if after > before =>
+                Err(ValidationError(format!("released_after {} is later than released_before {}", after, before))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How the words of a search have to match, `phrase` wants them all together in order.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    #[default]
+    Phrase,
+    All,
+    Any,
+}
+
+/// How many edits a search term can be from a word and still match it, `auto` scales
+/// with the length of the term.
+#[derive(Clone, Copy, Deserialize, PartialEq, Debug)]
+#[serde(try_from = "String")]
+pub enum Fuzziness {
+    Auto,
+    Edits(u8),
+}
+
+impl TryFrom<String> for Fuzziness {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        match raw.to_lowercase().as_str() {
+            "auto" => Ok(Fuzziness::Auto),
+            "0" => Ok(Fuzziness::Edits(0)),
+            "1" => Ok(Fuzziness::Edits(1)),
+            "2" => Ok(Fuzziness::Edits(2)),
+            _ => Err(format!("fuzziness is auto, 0, 1 or 2, not {}", raw)),
+        }
+    }
+}
+
+/// A full text search and how to run it, `term` is the free text left once
+/// `qualifiers` have been parsed out of what was typed.
+#[derive(Clone, Debug)]
+pub struct SearchQuery {
+    pub term: String,
+    pub qualifiers: Vec<Qualifier>,
+    pub mode: MatchMode,
+    pub fuzziness: Option<Fuzziness>,
+    pub sort: Option<SearchSort>,
+    pub filter: MovieFilter,
+    pub facets: bool,
+    pub highlight: bool,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: i64,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct DecadeCount {
+    pub decade: i32,
+    pub count: i64,
+}
+
+/// Counts of matching movies per facet value, each facet is counted with every
+/// filter applied except its own, so its other values stay selectable.
+///
+/// Values are exactly what the filters take back.
+#[derive(Clone, Serialize, Debug)]
+pub struct Facets {
+    pub genre: Vec<FacetCount>,
+    pub language: Vec<FacetCount>,
+    pub country: Vec<FacetCount>,
+    pub decade: Vec<DecadeCount>,
+}
+
+/// Why a movie matched a search, the fragments of each field that matched with
+/// the matching words wrapped in `<em>`.
+#[derive(Clone, Serialize, Default, Debug)]
+pub struct SearchMatch {
+    pub highlights: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchResults {
+    pub page: Page<Either<HasId, Movie>>,
+    pub facets: Option<Facets>,
+    /// A corrected search term, only when the search found little.
+    pub did_you_mean: Option<String>,
+    /// By movie id, only when highlighting was asked for.
+    pub matches: Option<HashMap<Uuid, SearchMatch>>,
+}
+
+/// Just enough of a movie to tell it apart while typing its title.
+#[derive(Clone, Serialize, Debug)]
+pub struct TitleSuggestion {
+    pub id: Uuid,
+    pub title: String,
+    pub year: Option<i32>,
+}
+
+/// A movie the index wouldn't take, and why.
+#[derive(Clone, Debug)]
+pub struct IndexRejection {
+    pub movie: Movie,
+    pub reason: String,
+    /// Worth trying again later, the index was too busy or broken rather than the movie.
+    pub retryable: bool,
+}
+
+/// How each movie in a bulk request went.
+#[derive(Clone, Default, Debug)]
+pub struct BulkIndexed {
+    pub indexed: Vec<Movie>,
+    pub rejected: Vec<IndexRejection>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Page<T> {
     pub page_number: i64,
@@ -84,7 +351,22 @@ pub struct MovieChangeset {
     pub updated: Option<DateTime<Utc>>,
     pub indexed: Option<DateTime<Utc>>,
     pub foreign_url: Option<Option<String>>,
-    pub deleted: Option<DateTime<Utc>>,
+    pub deleted: Option<Option<DateTime<Utc>>>,
+}
+
+/// A movie that failed to index, retried at `retry_at` or given up on when that's empty.
+///
+/// `movie_updated` is the version that failed, editing the movie gets it another go.
+#[derive(Clone, Debug, Serialize, Insertable, Queryable)]
+#[table_name="index_failures"]
+pub struct IndexFailure {
+    pub movie_id: Uuid,
+    pub reason: String,
+    pub attempts: i32,
+    pub retry_at: Option<DateTime<Utc>>,
+    pub movie_updated: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -104,6 +386,14 @@ pub struct CreateMovieParams {
 }
 
 impl CreateMovieParams {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.title.trim().is_empty() {
+            return Err(ValidationError("title can't be blank".to_string()))
+        }
+
+        Ok(())
+    }
+
     fn create(&self) -> Movie {
         let now = Utc::now();
         let id_namespace = Uuid::new_v5(&Uuid::nil(), format!("{:?}", &self.release_date).as_bytes());
@@ -128,16 +418,26 @@ impl CreateMovieParams {
     }
 }
 
+/// Changes to a movie with JSON Merge Patch (RFC 7396) semantics: absent fields
+/// are left alone, `null` clears a field.
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateMovieParams {
+    #[serde(default, deserialize_with = "patch::present")]
     pub title: Option<String>,
+    #[serde(default, deserialize_with = "patch::present")]
     pub tagline: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch::present")]
     pub overview: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch::cleared_if_null")]
     pub spoken_languages: Option<Vec<Language>>,
+    #[serde(default, deserialize_with = "patch::cleared_if_null")]
     pub production_countries: Option<Vec<Country>>,
+    #[serde(default, deserialize_with = "patch::cleared_if_null")]
     pub genres: Option<Vec<Genre>>,
+    #[serde(default, deserialize_with = "patch::present")]
     pub release_date: Option<Option<NaiveDate>>,
+    #[serde(default, deserialize_with = "patch::present")]
     pub foreign_url: Option<Option<String>>,
 }
 
@@ -162,9 +462,9 @@ impl UpdateMovieParams {
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct IndexMovie;
+pub struct DeleteMovie;
 
-impl IndexMovie {
+impl DeleteMovie {
     fn update(&self) -> MovieChangeset {
         let now = Utc::now();
         MovieChangeset {
@@ -175,19 +475,19 @@ impl IndexMovie {
             production_countries: None,
             genres: None,
             release_date: None,
-            updated: None,
-            indexed: Some(now),
+            updated: Some(now),
+            indexed: None,
             foreign_url: None,
-            deleted: None,
+            deleted: Some(Some(now)),
         }
     }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct DeleteMovie;
+pub struct RestoreMovie;
 
-impl DeleteMovie {
+impl RestoreMovie {
     fn update(&self) -> MovieChangeset {
         let now = Utc::now();
         MovieChangeset {
@@ -201,7 +501,7 @@ impl DeleteMovie {
             updated: Some(now),
             indexed: None,
             foreign_url: None,
-            deleted: Some(now),
+            deleted: Some(None),
         }
     }
 }
\ No newline at end of file
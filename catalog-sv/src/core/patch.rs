@@ -0,0 +1,124 @@
+use serde::{Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::core::{Movie, UpdateMovieParams};
+use crate::core::error::Error;
+use crate::core::error::Error::{InvalidPatch, PatchTestFailed};
+
+/// For `#[serde(default)]` fields, so a key that's present (even as `null`)
+/// is told apart from one that's absent.
+pub fn present<'de, T, D>(de: D) -> Result<Option<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+{
+    T::deserialize(de).map(Some)
+}
+
+/// For `#[serde(default)]` array fields, which can't be null, so `null` clears them.
+pub fn cleared_if_null<'de, T, D>(de: D) -> Result<Option<Vec<T>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+{
+    Option::<Vec<T>>::deserialize(de).map(|v| Some(v.unwrap_or_default()))
+}
+
+/// An RFC 6902 JSON Patch operation, only supported on a movie's array fields.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Test { path: String, value: Value },
+}
+
+const PATCHABLE: [&str; 3] = ["genres", "spokenLanguages", "productionCountries"];
+
+enum Position {
+    Whole,
+    At(usize),
+    End,
+}
+
+fn parse_path(path: &str) -> Result<(&str, Position), Error> {
+    let mut segments = path.splitn(3, '/').skip(1);
+    let field = segments.next()
+        .filter(|f| PATCHABLE.iter().any(|p| p == f))
+        .ok_or_else(|| InvalidPatch(format!("can't patch {}, only {}", path, PATCHABLE.join(", "))))?;
+
+    let position = match segments.next() {
+        None => Position::Whole,
+        Some("-") => Position::End,
+        Some(idx) => idx.parse()
+            .map(Position::At)
+            .map_err(|_| InvalidPatch(format!("{} is not an array index", path)))?,
+    };
+
+    Ok((field, position))
+}
+
+fn apply_operation(doc: &mut Map<String, Value>, op: PatchOperation) -> Result<(), Error> {
+    let path = match &op {
+        PatchOperation::Add { path, .. }
+        | PatchOperation::Remove { path }
+        | PatchOperation::Replace { path, .. }
+        | PatchOperation::Test { path, .. } => path.clone(),
+    };
+    let (field, position) = parse_path(&path)?;
+    let items = doc.get_mut(field)
+        .and_then(Value::as_array_mut)
+        .expect("patchable fields are always arrays");
+
+    let out_of_bounds = || InvalidPatch(format!("{} is out of bounds", path));
+
+    match (op, position) {
+        (PatchOperation::Add { value, .. }, Position::Whole)
+        | (PatchOperation::Replace { value, .. }, Position::Whole) => match value {
+            Value::Array(replacement) => *items = replacement,
+            _ => return Err(InvalidPatch(format!("{} can only be replaced with an array", path))),
+        },
+        (PatchOperation::Add { value, .. }, Position::End) => items.push(value),
+        (PatchOperation::Add { value, .. }, Position::At(i)) if i <= items.len() => items.insert(i, value),
+        (PatchOperation::Remove { .. }, Position::Whole) => items.clear(),
+        (PatchOperation::Remove { .. }, Position::At(i)) if i < items.len() => { items.remove(i); }
+        (PatchOperation::Replace { value, .. }, Position::At(i)) if i < items.len() => items[i] = value,
+        (PatchOperation::Test { value, .. }, Position::Whole) if value.as_array() == Some(&*items) => (),
+        (PatchOperation::Test { value, .. }, Position::At(i)) if items.get(i) == Some(&value) => (),
+        (PatchOperation::Test { .. }, _) => return Err(PatchTestFailed(path)),
+        _ => return Err(out_of_bounds()),
+    }
+
+    Ok(())
+}
+
+fn take_field<T: DeserializeOwned>(doc: &mut Map<String, Value>, field: &str) -> Result<Vec<T>, Error> {
+    let value = doc.remove(field).unwrap_or_default();
+    serde_json::from_value(value)
+        .map_err(|e| InvalidPatch(format!("/{} {}", field, e)))
+}
+
+/// Applies `ops` to the array fields of `movie`, giving the update that gets it there.
+///
+/// Operations are all or nothing, any failure leaves nothing to write.
+pub fn apply(movie: &Movie, ops: Vec<PatchOperation>) -> Result<UpdateMovieParams, Error> {
+    let mut doc = Map::new();
+    doc.insert("genres".to_string(), serde_json::to_value(&movie.genres)?);
+    doc.insert("spokenLanguages".to_string(), serde_json::to_value(&movie.spoken_languages)?);
+    doc.insert("productionCountries".to_string(), serde_json::to_value(&movie.production_countries)?);
+
+    ops.into_iter().try_for_each(|op| apply_operation(&mut doc, op))?;
+
+    Ok(UpdateMovieParams {
+        title: None,
+        tagline: None,
+        overview: None,
+        spoken_languages: Some(take_field(&mut doc, "spokenLanguages")?),
+        production_countries: Some(take_field(&mut doc, "productionCountries")?),
+        genres: Some(take_field(&mut doc, "genres")?),
+        release_date: None,
+        foreign_url: None,
+    })
+}
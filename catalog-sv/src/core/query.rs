@@ -0,0 +1,203 @@
+use crate::core::error::Error;
+use crate::core::error::Error::QuerySyntaxError;
+
+/// A restriction typed into a search, like `genre:horror` or `year:1978..1985`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Qualifier {
+    Genre(Vec<String>),
+    Language(Vec<String>),
+    Country(Vec<String>),
+    /// Inclusive, either end can be left open.
+    Year(Option<i32>, Option<i32>),
+}
+
+/// A search split into the free text that's scored and the qualifiers that filter.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParsedQuery {
+    pub text: String,
+    pub qualifiers: Vec<Qualifier>,
+}
+
+struct Token<'a> {
+    raw: &'a str,
+    offset: usize,
+}
+
+fn syntax_error(token: &Token, message: &str) -> Error {
+    QuerySyntaxError {
+        message: message.to_string(),
+        token: token.raw.to_string(),
+        offset: token.offset,
+    }
+}
+
+/// Splits on whitespace, except inside double quotes.
+fn tokenize(raw: &str) -> Result<Vec<Token<'_>>, Error> {
+    let mut tokens = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut quoted = false;
+
+    for (i, c) in raw.char_indices() {
+        match (start, c) {
+            (None, c) if c.is_whitespace() => (),
+            (None, c) => {
+                start = Some(i);
+                quoted = c == '"';
+            }
+            (Some(_), '"') => quoted = !quoted,
+            (Some(s), c) if c.is_whitespace() && !quoted => {
+                tokens.push(Token { raw: &raw[s..i], offset: s });
+                start = None;
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(s) = start {
+        let token = Token { raw: &raw[s..], offset: s };
+        if quoted {
+            return Err(syntax_error(&token, "unterminated quote"))
+        }
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn values(token: &Token, value: &str) -> Result<Vec<String>, Error> {
+    let values: Vec<String> = value.split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    if values.is_empty() {
+        Err(syntax_error(token, "qualifier needs a value"))
+    } else {
+        Ok(values)
+    }
+}
+
+fn year(token: &Token, raw: &str) -> Result<Option<i32>, Error> {
+    match raw {
+        "" => Ok(None),
+        y if y.len() == 4 && y.chars().all(|c| c.is_ascii_digit()) => y.parse()
+            .map(Some)
+            .map_err(|_| syntax_error(token, "years are four digits")),
+        _ => Err(syntax_error(token, "years are four digits")),
+    }
+}
+
+fn year_range(token: &Token, value: &str) -> Result<Qualifier, Error> {
+    let (from, to) = match value.find("..") {
+        None => {
+            let y = year(token, value)?;
+            (y, y)
+        }
+        Some(i) => (year(token, &value[..i])?, year(token, &value[i + 2..])?),
+    };
+
+    match (from, to) {
+        (None, None) => Err(syntax_error(token, "year needs a year or a range like 1978..1985")),
+        (Some(f), Some(t)) if f > t => Err(syntax_error(token, "year range ends before it starts")),
+        _ => Ok(Qualifier::Year(from, to)),
+    }
+}
+
+/// The qualifier a token is, `None` if it's free text.
+///
+/// Only known qualifier names count, so text like `Star Trek: Voyager` still searches as text.
+fn qualifier(token: &Token) -> Result<Option<Qualifier>, Error> {
+    let colon = match token.raw.find(':') {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+
+    let name = token.raw[..colon].to_lowercase();
+    let value = token.raw[colon + 1..].trim_matches('"');
+
+    let parsed = match name.as_str() {
+        "genre" => Qualifier::Genre(values(token, value)?),
+        "lang" | "language" => Qualifier::Language(values(token, value)?),
+        "country" => Qualifier::Country(values(token, value)?),
+        "year" => year_range(token, value)?,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(parsed))
+}
+
+/// Parses a search like `genre:horror year:1978..1985 lang:it suspiria`.
+///
+/// Qualifier values can be comma separated to match any of them, and quoted
+/// when they have spaces, `genre:"science fiction"`.
+pub fn parse(raw: &str) -> Result<ParsedQuery, Error> {
+    let mut text = Vec::new();
+    let mut qualifiers = Vec::new();
+
+    for token in tokenize(raw)? {
+        match qualifier(&token)? {
+            Some(q) => qualifiers.push(q),
+            None => text.push(token.raw.replace('"', "")),
+        }
+    }
+
+    Ok(ParsedQuery {
+        text: text.join(" "),
+        qualifiers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_error_at(raw: &str) -> (String, usize) {
+        match parse(raw) {
+            Err(QuerySyntaxError { token, offset, .. }) => (token, offset),
+            other => panic!("expected a syntax error for {:?}, got {:?}", raw, other),
+        }
+    }
+
+    #[test]
+    fn it_keeps_plain_text() {
+        let parsed = parse("  star   trek: voyager ").unwrap();
+        assert_eq!(parsed.text, "star trek: voyager");
+        assert!(parsed.qualifiers.is_empty());
+    }
+
+    #[test]
+    fn it_reads_quoted_phrases() {
+        let parsed = parse(r#"genre:"science fiction" "the thing" lang:en,fr"#).unwrap();
+        assert_eq!(parsed.text, "the thing");
+        assert_eq!(parsed.qualifiers, vec![
+            Qualifier::Genre(vec!["science fiction".to_string()]),
+            Qualifier::Language(vec!["en".to_string(), "fr".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn it_reads_year_ranges() {
+        let parsed = parse("year:1978..1985 year:1990.. year:..2000 year:1999").unwrap();
+        assert_eq!(parsed.qualifiers, vec![
+            Qualifier::Year(Some(1978), Some(1985)),
+            Qualifier::Year(Some(1990), None),
+            Qualifier::Year(None, Some(2000)),
+            Qualifier::Year(Some(1999), Some(1999)),
+        ]);
+    }
+
+    #[test]
+    fn it_points_at_malformed_qualifiers() {
+        assert_eq!(syntax_error_at("suspiria year:1985..1978"), ("year:1985..1978".to_string(), 9));
+        assert_eq!(syntax_error_at("alien genre:"), ("genre:".to_string(), 6));
+        assert_eq!(syntax_error_at("year:.."), ("year:..".to_string(), 0));
+        assert_eq!(syntax_error_at(r#"alien "the thing"#), (r#""the thing"#.to_string(), 6));
+    }
+
+    #[test]
+    fn it_rejects_years_that_are_not_four_digits() {
+        assert_eq!(syntax_error_at("year:-123"), ("year:-123".to_string(), 0));
+        assert_eq!(syntax_error_at("lang:en year:+999..2000"), ("year:+999..2000".to_string(), 8));
+        assert_eq!(syntax_error_at("year:85"), ("year:85".to_string(), 0));
+    }
+}
@@ -0,0 +1,46 @@
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Text};
+
+use crate::core::MovieFilter;
+use crate::db::keyset::MovieQuery;
+use crate::db::types::{PgCountry, PgGenre, PgLanguage};
+
+// the same functions the GIN indexes are built on, so they get used, they lowercase
+// everything so filters match ignoring case
+sql_function!(fn movie_genre_names(genres: Array<PgGenre>) -> Array<Text>);
+sql_function!(fn movie_language_codes(languages: Array<PgLanguage>) -> Array<Text>);
+sql_function!(fn movie_country_codes(countries: Array<PgCountry>) -> Array<Text>);
+
+/// Narrows `query` to the movies matching every part of `filter`.
+pub fn apply(mut query: MovieQuery, filter: &MovieFilter) -> MovieQuery {
+    use crate::db::schema::movies::dsl::*;
+
+    let lowercase = |values: Vec<String>| -> Vec<String> {
+        values.iter().map(|v| v.to_lowercase()).collect()
+    };
+
+    let wanted_genres = lowercase(filter.genres());
+    if !wanted_genres.is_empty() {
+        query = query.filter(movie_genre_names(genres).overlaps_with(wanted_genres));
+    }
+
+    let wanted_languages = lowercase(filter.languages());
+    if !wanted_languages.is_empty() {
+        query = query.filter(movie_language_codes(spoken_languages).overlaps_with(wanted_languages));
+    }
+
+    let wanted_countries = lowercase(filter.countries());
+    if !wanted_countries.is_empty() {
+        query = query.filter(movie_country_codes(production_countries).overlaps_with(wanted_countries));
+    }
+
+    if let Some(after) = filter.released_after {
+        query = query.filter(release_date.ge(after));
+    }
+
+    if let Some(before) = filter.released_before {
+        query = query.filter(release_date.le(before));
+    }
+
+    query
+}
@@ -0,0 +1,172 @@
+use base64::URL_SAFE_NO_PAD;
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::core::{Movie, MovieSort};
+use crate::core::error::Error;
+use crate::core::error::Error::{AnchorDecodeError, AnchorParseError};
+use crate::db::schema::movies;
+
+pub type MovieQuery = movies::BoxedQuery<'static, Pg>;
+pub type MoviePredicate = Box<dyn BoxableExpression<movies::table, Pg, SqlType = Bool>>;
+
+/// Where a page of movies ended, holding every sort key so it works for any `MovieSort`,
+/// but only the sort it was made for.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MovieAnchor {
+    pub sort: MovieSort,
+    title: String,
+    release_date: Option<NaiveDate>,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    id: Uuid,
+    pub page_number: i64,
+}
+
+impl MovieAnchor {
+    pub fn after(last: &Movie, sort: MovieSort, page_number: i64) -> Self {
+        MovieAnchor {
+            sort,
+            title: last.title.clone(),
+            release_date: last.release_date,
+            created: last.created,
+            updated: last.updated,
+            id: last.id,
+            page_number,
+        }
+    }
+}
+
+pub fn deserialize_anchor(raw: String) -> Result<MovieAnchor, Error> {
+    let octets = base64::decode_config(raw, URL_SAFE_NO_PAD)
+        .map_err(AnchorDecodeError)?;
+
+    rmp_serde::from_read_ref(octets.as_slice())
+        .map_err(AnchorParseError)
+}
+
+pub fn serialize_anchor(anch: MovieAnchor) -> String {
+    let d = rmp_serde::to_vec(&anch).unwrap();
+    base64::encode_config(d, URL_SAFE_NO_PAD)
+}
+
+/// Every order ends on `id` so it's total, which keyset pagination relies on.
+pub fn order(query: MovieQuery, sort: MovieSort) -> MovieQuery {
+    use crate::db::schema::movies::dsl::*;
+
+    match sort {
+        MovieSort::Title => query.order((title.asc(), release_date.desc().nulls_last(), id.asc())),
+        MovieSort::TitleDesc => query.order((title.desc(), release_date.desc().nulls_last(), id.asc())),
+        MovieSort::ReleaseDate => query.order((release_date.asc().nulls_last(), title.asc(), id.asc())),
+        MovieSort::ReleaseDateDesc => query.order((release_date.desc().nulls_last(), title.asc(), id.asc())),
+        MovieSort::Created => query.order((created.asc(), id.asc())),
+        MovieSort::CreatedDesc => query.order((created.desc(), id.asc())),
+        MovieSort::Updated => query.order((updated.asc(), id.asc())),
+        MovieSort::UpdatedDesc => query.order((updated.desc(), id.asc())),
+    }
+}
+
+fn title_after(anch: &MovieAnchor, ascending: bool) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+
+    if ascending {
+        Box::new(title.gt(anch.title.clone()))
+    } else {
+        Box::new(title.lt(anch.title.clone()))
+    }
+}
+
+fn title_at(anch: &MovieAnchor) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+    Box::new(title.eq(anch.title.clone()))
+}
+
+// release dates sort nulls last both ways, so nothing but another null comes after one
+fn release_date_after(anch: &MovieAnchor, ascending: bool) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+
+    match anch.release_date {
+        None => Box::new(sql::<Bool>("FALSE")),
+        Some(r) if ascending => Box::new(release_date.gt(r).or(release_date.is_null())),
+        Some(r) => Box::new(release_date.lt(r).or(release_date.is_null())),
+    }
+}
+
+fn release_date_at(anch: &MovieAnchor) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+
+    match anch.release_date {
+        None => Box::new(release_date.is_null()),
+        Some(r) => Box::new(release_date.eq(r)),
+    }
+}
+
+fn created_after(anch: &MovieAnchor, ascending: bool) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+
+    if ascending {
+        Box::new(created.gt(anch.created))
+    } else {
+        Box::new(created.lt(anch.created))
+    }
+}
+
+fn created_at(anch: &MovieAnchor) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+    Box::new(created.eq(anch.created))
+}
+
+fn updated_after(anch: &MovieAnchor, ascending: bool) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+
+    if ascending {
+        Box::new(updated.gt(anch.updated))
+    } else {
+        Box::new(updated.lt(anch.updated))
+    }
+}
+
+fn updated_at(anch: &MovieAnchor) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+    Box::new(updated.eq(anch.updated))
+}
+
+/// Movies that come after the anchor in its sort, for keys `(a, b, id)` that's
+/// `a after OR (a at AND (b after OR (b at AND id after)))`.
+pub fn after(anch: &MovieAnchor) -> MoviePredicate {
+    use crate::db::schema::movies::dsl::*;
+
+    let keys = match anch.sort {
+        MovieSort::Title => vec![
+            (title_after(anch, true), title_at(anch)),
+            (release_date_after(anch, false), release_date_at(anch)),
+        ],
+        MovieSort::TitleDesc => vec![
+            (title_after(anch, false), title_at(anch)),
+            (release_date_after(anch, false), release_date_at(anch)),
+        ],
+        MovieSort::ReleaseDate => vec![
+            (release_date_after(anch, true), release_date_at(anch)),
+            (title_after(anch, true), title_at(anch)),
+        ],
+        MovieSort::ReleaseDateDesc => vec![
+            (release_date_after(anch, false), release_date_at(anch)),
+            (title_after(anch, true), title_at(anch)),
+        ],
+        MovieSort::Created => vec![(created_after(anch, true), created_at(anch))],
+        MovieSort::CreatedDesc => vec![(created_after(anch, false), created_at(anch))],
+        MovieSort::Updated => vec![(updated_after(anch, true), updated_at(anch))],
+        MovieSort::UpdatedDesc => vec![(updated_after(anch, false), updated_at(anch))],
+    };
+
+    let id_after: MoviePredicate = Box::new(id.gt(anch.id));
+
+    keys.into_iter()
+        .rev()
+        .fold(id_after, |rest, (key_after, key_at)| Box::new(key_after.or(key_at.and(rest))))
+}
@@ -1,150 +1,96 @@
-use base64::URL_SAFE_NO_PAD;
-use chrono::NaiveDate;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use diesel::dsl::sql;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::sql_types::Bool;
 use either::Either;
 use either::Either::{Left, Right};
 use log::debug;
 use r2d2::Pool;
-use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::core::{HasId, Movie, MovieChangeset, Page};
+use crate::core::{IndexFailure, Movie, MovieChangeset, MovieFilter, MovieSort, Page};
 use crate::core::error::Error;
-use crate::core::error::Error::{AnchorDecodeError, AnchorParseError, DBQueryError};
+use crate::core::error::Error::{DBQueryError, ValidationError};
+use crate::db::keyset::{deserialize_anchor, MovieAnchor, MoviePredicate, serialize_anchor};
 use crate::db::pagination::*;
+use crate::db::upsert::*;
 
 pub mod schema;
 pub mod types;
+mod filter;
+mod keyset;
 mod pagination;
+mod upsert;
 
 pub type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
 pub type DbConnectionPool = Pool<ConnectionManager<PgConnection>>;
 
-// todo: extract all this pagination stuff into a lib and clean it tf up
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct MovieAnchor {
-    title: String,
-    release_date: Option<NaiveDate>,
-    id: Uuid,
-    page_number: i64,
-}
+/// A page of movies matching `filter` in `sort` order, following on from `anchor` if there is one.
+///
+/// The anchor remembers the sort it was made for, `sort` can be left out when following
+/// one, but can't change the order part way through.
+pub fn find_movies(
+    conn: &DbConnection,
+    page_size: i64,
+    sort: Option<MovieSort>,
+    filter: &MovieFilter,
+    anchor: &Option<String>,
+) -> Result<Page<Movie>, Error> {
+    use schema::movies::dsl::*;
 
-fn deserialize_anchor(raw: String) -> Result<MovieAnchor, Error> {
-    let octets = base64::decode_config(raw, URL_SAFE_NO_PAD)
-        .map_err(AnchorDecodeError)?;
+    let anch = match anchor {
+        None => None,
+        Some(a) => Some(deserialize_anchor(a.to_string())?),
+    };
 
-    rmp_serde::from_read_ref(octets.as_slice())
-        .map_err(AnchorParseError)
-}
+    debug!("deserialized anchor {:?}", anch);
 
-fn serialize_anchor(anch: MovieAnchor) -> String {
-    let d = rmp_serde::to_vec(&anch).unwrap();
-    base64::encode_config(d, URL_SAFE_NO_PAD)
-}
+    let (sort, page_number) = match (&anch, sort) {
+        (None, s) => (s.unwrap_or_default(), 1),
+        (Some(a), Some(s)) if a.sort != s =>
+            return Err(ValidationError(format!("anchor is for sort {}, not {}", a.sort, s))),
+        (Some(a), _) => (a.sort, a.page_number),
+    };
 
-pub fn find_movies(conn: &DbConnection, page_size: i64, anchor: &Option<String>) -> Result<Page<Movie>, Error> {
+    let mut query = filter::apply(
+        movies.filter(deleted.is_null()).into_boxed(),
+        filter,
+    );
 
-    use schema::movies::dsl::*;
+    if let Some(a) = &anch {
+        query = query.filter(keyset::after(a));
+    }
 
-    match anchor {
-        None => {
-            let page_number = 1;
-
-            let query = movies
-                .filter(deleted.is_null())
-                .order((title.asc(), release_date.desc().nulls_last()))
-                .count_remaining(page_size);
-
-            debug!("{}", diesel::debug_query(&query));
-
-            let (items, c) = query
-                .load_and_count_remaining::<Movie>(conn)?;
-
-            debug!("found items {:?} remaining={}", items, c);
-
-            let next_anchor = if !items.is_empty() && c > 0 {
-                let last = items.last().unwrap();
-                let a = MovieAnchor {
-                    title: last.title.clone(),
-                    release_date: last.release_date.clone(),
-                    id: last.id.clone(),
-                    page_number: page_number + 1,
-                };
-                debug!("anchor generated {:?}", a);
-                Some(serialize_anchor(a))
-            } else {
-                None
-            };
-
-            debug!("serialized_anchor={:?}", next_anchor);
-
-            Ok(Page {
-                page_number,
-                next_anchor,
-                items,
-            })
-        }
+    let query = keyset::order(query, sort)
+        .count_remaining(page_size);
 
-        Some(a) => {
-            let anch = deserialize_anchor(a.to_string())?;
-
-            debug!("deserialized anchor {:?}", anch);
-
-            let filtered = movies.filter(title.gt(&anch.title));
-
-            let next_q = match anch.release_date {
-                Some(r) =>
-                    filtered.or_filter(
-                        title.eq(&anch.title)
-                            .and(release_date.lt(r)
-                                .or(release_date.eq(r)
-                                    .and(id.gt(&anch.id))
-                                )
-                            )
-                    ).into_boxed(),
-                None =>
-                    filtered.or_filter(
-                        title.eq(&anch.title)
-                            .and(release_date.is_null()
-                                .and(id.gt(&anch.id))
-                            )
-                    ).into_boxed()
-            };
-
-            let query = next_q
-                .filter(deleted.is_null())
-                .order((title.asc(), release_date.desc().nulls_last()))
-                .count_remaining(page_size);
-
-            debug!("{}", diesel::debug_query(&query));
-
-            let (items, c) = query
-                .load_and_count_remaining::<Movie>(conn)?;
-
-            debug!("found items {:?} remaining={}", items, c);
-
-            let next_anchor = if !items.is_empty() && c > 0 {
-                let last = items.last().unwrap();
-
-                Some(serialize_anchor(MovieAnchor {
-                    title: last.title.clone(),
-                    release_date: last.release_date.clone(),
-                    id: last.id.clone(),
-                    page_number: anch.page_number + 1,
-                }))
-            } else {
-                None
-            };
-
-            Ok(Page {
-                page_number: anch.page_number,
-                next_anchor,
-                items,
-            })
+    debug!("{}", diesel::debug_query(&query));
+
+    let (items, c) = query
+        .load_and_count_remaining::<Movie>(conn)?;
+
+    debug!("found items {:?} remaining={}", items, c);
+
+    let next_anchor = match items.last() {
+        Some(last) if c > 0 => {
+            let a = MovieAnchor::after(last, sort, page_number + 1);
+            debug!("anchor generated {:?}", a);
+            Some(serialize_anchor(a))
         }
-    }
+        _ => None,
+    };
+
+    debug!("serialized_anchor={:?}", next_anchor);
+
+    Ok(Page {
+        page_number,
+        next_anchor,
+        items,
+    })
 }
 
 pub fn find_one_movie(conn: &DbConnection, movie_id: Uuid) -> Result<Option<Movie>, Error> {
@@ -172,39 +118,113 @@ pub fn find_movies_with_ids(conn: &DbConnection, movie_ids: Vec<Uuid>) -> Result
     })
 }
 
-pub fn create_movie(conn: &DbConnection, movie: Movie) -> Result<Either<HasId, Movie>, Error> {
+/// Inserts `movie`, or revives it over a soft deleted movie with the same id.
+///
+/// A live movie with the same id is left alone and returned on the `Left`.
+pub fn create_movie(conn: &DbConnection, movie: Movie) -> Result<Either<Movie, Movie>, Error> {
+    create_movies(conn, &[movie])
+        .map(|mut created| created.remove(0))
+}
+
+/// Like `create_movie` for many movies in one statement, results are in the order
+/// of `new_movies`, which can't contain the same id twice.
+pub fn create_movies(conn: &DbConnection, new_movies: &[Movie]) -> Result<Vec<Either<Movie, Movie>>, Error> {
+    use diesel::pg::upsert::excluded;
+    use schema::movies;
+    use schema::movies::dsl::*;
+
+    if new_movies.is_empty() {
+        return Ok(Vec::new())
+    }
+
+    conn.transaction(|| {
+        let query = diesel::insert_into(movies::table)
+            .values(new_movies)
+            .on_conflict(id)
+            .do_update()
+            .set((
+                title.eq(excluded(title)),
+                tagline.eq(excluded(tagline)),
+                overview.eq(excluded(overview)),
+                spoken_languages.eq(excluded(spoken_languages)),
+                production_countries.eq(excluded(production_countries)),
+                genres.eq(excluded(genres)),
+                release_date.eq(excluded(release_date)),
+                created.eq(excluded(created)),
+                updated.eq(excluded(updated)),
+                indexed.eq(excluded(indexed)),
+                foreign_url.eq(excluded(foreign_url)),
+                deleted.eq(excluded(deleted)),
+            ))
+            .update_where(deleted.is_not_null(), movies::all_columns);
+
+        debug!("{}", diesel::debug_query(&query));
+
+        let mut created_movies: HashMap<Uuid, Movie> = query
+            .get_results::<Movie>(conn)?
+            .into_iter()
+            .map(|m| (m.id, m))
+            .collect();
+
+        let conflicting_ids: Vec<Uuid> = new_movies.iter()
+            .map(|m| m.id)
+            .filter(|i| !created_movies.contains_key(i))
+            .collect();
+
+        // postgres keeps the conflicting rows locked until the transaction ends
+        let mut existing: HashMap<Uuid, Movie> = if conflicting_ids.is_empty() {
+            HashMap::new()
+        } else {
+            movies.filter(id.eq_any(&conflicting_ids))
+                .load::<Movie>(conn)?
+                .into_iter()
+                .map(|m| (m.id, m))
+                .collect()
+        };
+
+        new_movies.iter()
+            .map(|m| created_movies.remove(&m.id).map(Right)
+                .or_else(|| existing.remove(&m.id).map(Left))
+                .ok_or(diesel::result::Error::NotFound))
+            .collect()
+    }).map_err(DBQueryError)
+}
+
+pub fn update_movie(
+    conn: &DbConnection,
+    movie_id: Uuid,
+    movie: MovieChangeset,
+    expected_versions: &Option<Vec<DateTime<Utc>>>,
+) -> Result<Option<Movie>, Error> {
     use schema::movies;
     use schema::movies::dsl::*;
 
-    let query = diesel::insert_into(movies::table)
-        .values(&movie)
-        .on_conflict(id)
-        // todo: add extension to support where clause on conflict updates
-        // .do_update()
-        // .set(&movie)
-        // .filter(deleted.is_not_null())
-        .do_nothing();
+    let mut query = diesel::update(movies::table)
+        .set(&movie)
+        .filter(id.eq(&movie_id))
+        .filter(deleted.is_null())
+        .into_boxed::<diesel::pg::Pg>();
+
+    if let Some(versions) = expected_versions {
+        query = query.filter(updated.eq_any(versions));
+    }
 
     debug!("{}", diesel::debug_query(&query));
 
-    let option: Option<Movie> = query
+    query
         .get_result(conn)
         .optional()
-        .map_err(DBQueryError)?;
-
-    Ok(option.map_or(Left(HasId {
-        id: movie.id.clone()
-    }), |r| Right(r)))
+        .map_err(DBQueryError)
 }
 
-pub fn update_movie(conn: &DbConnection, movie_id: Uuid, movie: MovieChangeset) -> Result<Option<Movie>, Error> {
+pub fn restore_movie(conn: &DbConnection, movie_id: Uuid, movie: MovieChangeset) -> Result<Option<Movie>, Error> {
     use schema::movies;
     use schema::movies::dsl::*;
 
     let query = diesel::update(movies::table)
         .set(&movie)
         .filter(id.eq(&movie_id))
-        .filter(deleted.is_null());
+        .filter(deleted.is_not_null());
 
     debug!("{}", diesel::debug_query(&query));
 
@@ -214,17 +234,23 @@ pub fn update_movie(conn: &DbConnection, movie_id: Uuid, movie: MovieChangeset)
         .map_err(DBQueryError)
 }
 
-pub fn update_movies(conn: &DbConnection, movie_ids: Vec<Uuid>, movie: MovieChangeset) -> Result<Vec<Movie>, Error> {
+/// Marks movies indexed at the version that was read, leaving any that have changed
+/// since for the next round.
+pub fn mark_indexed(conn: &DbConnection, read: &[Movie]) -> Result<Vec<Movie>, Error> {
     use schema::movies;
     use schema::movies::dsl::*;
 
-    if movie_ids.is_empty() {
+    if read.is_empty() {
         return Ok(Vec::new())
     }
 
+    let none: MoviePredicate = Box::new(sql::<Bool>("FALSE"));
+    let unchanged = read.iter()
+        .fold(none, |rest, m| Box::new(rest.or(id.eq(m.id).and(updated.eq(m.updated)))));
+
     let query = diesel::update(movies::table)
-        .set(&movie)
-        .filter(id.eq_any(&movie_ids));
+        .set(indexed.eq(updated.nullable()))
+        .filter(unchanged);
 
     debug!("{}", diesel::debug_query(&query));
 
@@ -249,13 +275,14 @@ pub fn delete_movie(conn: &DbConnection, movie_id: Uuid) -> Result<bool, Error>
         .map(|r| r > 0)
 }
 
-pub fn delete_soft_deleted(conn: &DbConnection) -> Result<usize, Error> {
+pub fn delete_soft_deleted(conn: &DbConnection, deleted_before: DateTime<Utc>) -> Result<usize, Error> {
     use schema::movies::dsl::*;
 
     let query = diesel::delete(schema::movies::table)
         .filter(deleted.is_not_null()
             .and(indexed.is_not_null())
-            .and(deleted.lt(indexed)))
+            .and(deleted.le(indexed))
+            .and(deleted.lt(deleted_before)))
         .into_boxed::<diesel::pg::Pg>();
 
     debug!("{}", diesel::debug_query(&query));
@@ -268,8 +295,14 @@ pub fn delete_soft_deleted(conn: &DbConnection) -> Result<usize, Error> {
 pub fn find_stale_indexed(conn: &DbConnection, page_size: i64) -> Result<Vec<Movie>, Error> {
     use schema::movies::dsl::*;
 
+    // skips movies that failed to index and are waiting on a retry or were given up on,
+    // unless they've been edited since
     let query = movies
         .filter(indexed.is_null().or(indexed.lt(updated.nullable())))
+        .filter(sql::<Bool>(
+            "NOT EXISTS (SELECT 1 FROM index_failures f \
+             WHERE f.movie_id = movies.id AND f.movie_updated >= movies.updated \
+             AND (f.retry_at IS NULL OR f.retry_at > now()))"))
         .order(indexed.asc().nulls_first())
         .limit(page_size);
 
@@ -279,3 +312,157 @@ pub fn find_stale_indexed(conn: &DbConnection, page_size: i64) -> Result<Vec<Mov
         .get_results(conn)
         .map_err(DBQueryError)
 }
+
+/// Live movies in id order, for copying every movie into a new index a page at a time.
+pub fn find_movies_after_id(conn: &DbConnection, after: Option<Uuid>, page_size: i64) -> Result<Vec<Movie>, Error> {
+    use schema::movies::dsl::*;
+
+    let mut query = movies
+        .filter(deleted.is_null())
+        .into_boxed();
+
+    if let Some(a) = after {
+        query = query.filter(id.gt(a));
+    }
+
+    let query = query
+        .order(id.asc())
+        .limit(page_size);
+
+    debug!("{}", diesel::debug_query(&query));
+
+    query
+        .get_results(conn)
+        .map_err(DBQueryError)
+}
+
+/// Movies changed since `since`, deleted ones included, in the order they changed,
+/// `after` is the id of the last movie seen that changed at exactly `since`.
+pub fn find_movies_changed_since(
+    conn: &DbConnection,
+    since: DateTime<Utc>,
+    after: Option<Uuid>,
+    page_size: i64,
+) -> Result<Vec<Movie>, Error> {
+    use schema::movies::dsl::*;
+
+    let mut query = movies.into_boxed();
+
+    query = match after {
+        None => query.filter(updated.ge(since)),
+        Some(a) => query.filter(updated.gt(since).or(updated.eq(since).and(id.gt(a)))),
+    };
+
+    let query = query
+        .order((updated.asc(), id.asc()))
+        .limit(page_size);
+
+    debug!("{}", diesel::debug_query(&query));
+
+    query
+        .get_results(conn)
+        .map_err(DBQueryError)
+}
+
+/// Runs `f` in a transaction, or a savepoint when there's one going already.
+pub fn transaction<T, F>(conn: &DbConnection, f: F) -> Result<T, Error>
+    where F: FnOnce() -> Result<T, Error>
+{
+    conn.transaction(f)
+}
+
+pub fn find_index_failures_for(conn: &DbConnection, movie_ids: &[Uuid]) -> Result<Vec<IndexFailure>, Error> {
+    use schema::index_failures::dsl::*;
+
+    let query = index_failures
+        .filter(movie_id.eq_any(movie_ids));
+
+    debug!("{}", diesel::debug_query(&query));
+
+    query
+        .get_results(conn)
+        .map_err(DBQueryError)
+}
+
+/// Most recently failed first, `given_up` leaves out the ones still waiting on a retry.
+pub fn find_index_failures(conn: &DbConnection, given_up: bool, page_size: i64) -> Result<Vec<IndexFailure>, Error> {
+    use schema::index_failures::dsl::*;
+
+    let mut query = index_failures.into_boxed();
+
+    if given_up {
+        query = query.filter(retry_at.is_null());
+    }
+
+    let query = query
+        .order((updated.desc(), movie_id.asc()))
+        .limit(page_size);
+
+    debug!("{}", diesel::debug_query(&query));
+
+    query
+        .get_results(conn)
+        .map_err(DBQueryError)
+}
+
+pub fn save_index_failures(conn: &DbConnection, failures: &[IndexFailure]) -> Result<Vec<IndexFailure>, Error> {
+    use diesel::pg::upsert::excluded;
+    use schema::index_failures;
+    use schema::index_failures::dsl::*;
+
+    if failures.is_empty() {
+        return Ok(Vec::new())
+    }
+
+    let query = diesel::insert_into(index_failures::table)
+        .values(failures)
+        .on_conflict(movie_id)
+        .do_update()
+        .set((
+            reason.eq(excluded(reason)),
+            attempts.eq(excluded(attempts)),
+            retry_at.eq(excluded(retry_at)),
+            movie_updated.eq(excluded(movie_updated)),
+            updated.eq(excluded(updated)),
+        ));
+
+    debug!("{}", diesel::debug_query(&query));
+
+    query
+        .get_results(conn)
+        .map_err(DBQueryError)
+}
+
+pub fn delete_index_failures(conn: &DbConnection, movie_ids: &[Uuid]) -> Result<usize, Error> {
+    use schema::index_failures::dsl::*;
+
+    if movie_ids.is_empty() {
+        return Ok(0)
+    }
+
+    let query = diesel::delete(index_failures.filter(movie_id.eq_any(movie_ids)));
+
+    debug!("{}", diesel::debug_query(&query));
+
+    query
+        .execute(conn)
+        .map_err(DBQueryError)
+}
+
+/// Puts movies back in line for the indexer.
+pub fn clear_indexed(conn: &DbConnection, movie_ids: &[Uuid]) -> Result<usize, Error> {
+    use schema::movies::dsl::*;
+
+    if movie_ids.is_empty() {
+        return Ok(0)
+    }
+
+    let query = diesel::update(movies.filter(id.eq_any(movie_ids)))
+        .set(indexed.eq(None::<DateTime<Utc>>));
+
+    debug!("{}", diesel::debug_query(&query));
+
+    query
+        .execute(conn)
+        .map_err(DBQueryError)
+}
@@ -1,3 +1,15 @@
+table! {
+    index_failures (movie_id) {
+        movie_id -> Uuid,
+        reason -> Text,
+        attempts -> Int4,
+        retry_at -> Nullable<Timestamptz>,
+        movie_updated -> Timestamptz,
+        created -> Timestamptz,
+        updated -> Timestamptz,
+    }
+}
+
 table! {
     movies (id) {
         id -> Uuid,
@@ -15,3 +27,10 @@ table! {
         deleted -> Nullable<Timestamptz>,
     }
 }
+
+joinable!(index_failures -> movies (movie_id));
+
+allow_tables_to_appear_in_same_query!(
+    index_failures,
+    movies,
+);
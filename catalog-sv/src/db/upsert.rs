@@ -0,0 +1,57 @@
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::*;
+
+/// Diesel has no dsl for the `WHERE` postgres allows on `ON CONFLICT ... DO UPDATE`,
+/// rows that conflict and don't match it are left alone and aren't returned.
+///
+/// Only for `INSERT ... ON CONFLICT ... DO UPDATE` statements without a returning
+/// clause of their own, and with at least one row to insert.
+pub trait UpdateWhere: Sized {
+    fn update_where<P, R>(self, predicate: P, returning: R) -> ConditionalUpsert<Self, P, R>;
+}
+
+impl<T> UpdateWhere for T {
+    fn update_where<P, R>(self, predicate: P, returning: R) -> ConditionalUpsert<Self, P, R> {
+        ConditionalUpsert {
+            statement: self,
+            predicate,
+            returning,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConditionalUpsert<T, P, R> {
+    statement: T,
+    predicate: P,
+    returning: R,
+}
+
+impl<T, P, R> QueryId for ConditionalUpsert<T, P, R> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, P, R: Expression> Query for ConditionalUpsert<T, P, R> {
+    type SqlType = R::SqlType;
+}
+
+impl<T, P, R> RunQueryDsl<PgConnection> for ConditionalUpsert<T, P, R> {}
+
+impl<T, P, R> QueryFragment<Pg> for ConditionalUpsert<T, P, R>
+    where
+        T: QueryFragment<Pg>,
+        P: QueryFragment<Pg>,
+        R: QueryFragment<Pg>,
+{
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        self.statement.walk_ast(out.reborrow())?;
+        out.push_sql(" WHERE ");
+        self.predicate.walk_ast(out.reborrow())?;
+        out.push_sql(" RETURNING ");
+        self.returning.walk_ast(out.reborrow())?;
+        Ok(())
+    }
+}
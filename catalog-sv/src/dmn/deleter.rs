@@ -1,6 +1,5 @@
 use std::sync::Mutex;
 
-use actix_web::error::BlockingError;
 use actix_web::rt::time::{Instant, interval_at};
 use actix_web::web;
 use actix_web::web::Data;
@@ -11,23 +10,31 @@ use log::error;
 use crate::core::action;
 use crate::core::error::Error;
 use crate::db::{DbConnection, DbConnectionPool};
+use crate::dmn::Backoff;
 
-pub struct DeleteDaemon;
+pub struct DeleteDaemon {
+    retention: Duration,
+    backoff: Backoff,
+}
 
 impl DeleteDaemon {
-    fn new() -> Self {
-        DeleteDaemon
+    fn new(every: Duration, retention: Duration) -> Self {
+        DeleteDaemon {
+            retention,
+            backoff: Backoff::new(every, Duration::minutes(5)),
+        }
     }
 
     async fn delete(
         &self,
         pool: Data<DbConnectionPool>,
-    ) -> Result<usize, BlockingError<Error>> {
-        let conn: DbConnection = pool.get()
-            .expect("couldn't get db connection from pool");
+    ) -> Result<usize, Error> {
+        let conn: DbConnection = pool.get()?;
+        let retention = self.retention;
 
-        web::block(move || action::delete_soft_deleted(&conn))
+        web::block(move || action::delete_soft_deleted(&conn, retention))
             .await
+            .map_err(Error::from)
     }
 
     fn spawn_deleter(
@@ -40,20 +47,31 @@ impl DeleteDaemon {
                 Instant::now(),
                 every.to_std().expect("can't spawn on a negative interval"));
             while task.next().await.is_some() {
-                me.lock().unwrap().delete(pool.clone())
-                    .await
-                    .map_err(|err| error!("error deleting, {:?}", err))
-                    .ok(); // continue on after errors
+                let mut daemon = me.lock().unwrap();
+                if !daemon.backoff.ready() {
+                    continue;
+                }
+
+                match daemon.delete(pool.clone()).await {
+                    Ok(_) => daemon.backoff.succeeded(),
+                    Err(err) => {
+                        let delay = daemon.backoff.failed();
+                        error!("error deleting, backing off for {}s, {:?}", delay.num_seconds(), err);
+                    }
+                } // continue on after errors
             }
         })
     }
 
+    /// Purges movies once the index has caught up with their deletion and they've
+    /// been deleted for longer than `retention`, until then they can be restored.
     pub fn start(
         pool: Data<DbConnectionPool>,
         every: Duration,
+        retention: Duration,
     ) -> Data<Mutex<Self>> {
-        let me = Data::new(Mutex::new(DeleteDaemon::new()));
+        let me = Data::new(Mutex::new(DeleteDaemon::new(every, retention)));
         Self::spawn_deleter(me.clone(), pool.clone(), every);
         me
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,5 @@
 use std::sync::Mutex;
 
-use actix_web::error::BlockingError;
 use actix_web::rt::time::{Instant, interval_at};
 use actix_web::web;
 use actix_web::web::Data;
@@ -11,22 +10,26 @@ use log::error;
 use crate::core::{action, Movie};
 use crate::core::error::Error;
 use crate::db::{DbConnection, DbConnectionPool};
+use crate::dmn::Backoff;
 use crate::idx::IndexClient;
 
-pub struct IndexDaemon;
+pub struct IndexDaemon {
+    backoff: Backoff,
+}
 
 impl IndexDaemon {
-    fn new() -> Self {
-        IndexDaemon
+    fn new(every: Duration) -> Self {
+        IndexDaemon {
+            backoff: Backoff::new(every, Duration::minutes(5)),
+        }
     }
 
     async fn index(
         &self,
         pool: Data<DbConnectionPool>,
         client: Data<IndexClient>,
-    ) -> Result<Vec<Movie>, BlockingError<Error>> {
-        let conn1: DbConnection = pool.get()
-            .expect("couldn't get db connection from pool");
+    ) -> Result<Vec<Movie>, Error> {
+        let conn1: DbConnection = pool.get()?;
 
         let to_index = web::block(move || action::find_movies_to_index(&conn1, 10))
             .await?;
@@ -35,14 +38,13 @@ impl IndexDaemon {
             return Ok(Vec::new())
         }
 
-        let indexed = action::index_movies(&client, to_index).await
-            .map_err(BlockingError::Error)?;
+        let results = action::index_movies(&client, to_index).await?;
 
-        let conn2: DbConnection = pool.get()
-            .expect("couldn't get db connection from pool");
+        let conn2: DbConnection = pool.get()?;
 
-        web::block(move || action::mark_movies_indexed(&conn2, indexed))
+        web::block(move || action::record_index_results(&conn2, results))
             .await
+            .map_err(Error::from)
     }
 
     fn spawn_indexer(
@@ -56,10 +58,18 @@ impl IndexDaemon {
                 Instant::now(),
                 every.to_std().expect("can't spawn on a negative interval"));
             while task.next().await.is_some() {
-                me.lock().unwrap().index(pool.clone(), client.clone())
-                    .await
-                    .map_err(|err| error!("error indexing, {:?}", err))
-                    .ok(); // continue on after errors
+                let mut daemon = me.lock().unwrap();
+                if !daemon.backoff.ready() {
+                    continue;
+                }
+
+                match daemon.index(pool.clone(), client.clone()).await {
+                    Ok(_) => daemon.backoff.succeeded(),
+                    Err(err) => {
+                        let delay = daemon.backoff.failed();
+                        error!("error indexing, backing off for {}s, {:?}", delay.num_seconds(), err);
+                    }
+                } // continue on after errors
             }
         })
     }
@@ -69,8 +79,8 @@ impl IndexDaemon {
         client: Data<IndexClient>,
         every: Duration,
     ) -> Data<Mutex<Self>> {
-        let me = Data::new(Mutex::new(IndexDaemon::new()));
+        let me = Data::new(Mutex::new(IndexDaemon::new(every)));
         Self::spawn_indexer(me.clone(), pool.clone(), client.clone(), every);
         me
     }
-}
\ No newline at end of file
+}
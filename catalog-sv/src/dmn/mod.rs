@@ -1,2 +1,45 @@
+use actix_web::rt::time::Instant;
+use chrono::Duration;
+
 pub mod deleter;
 pub mod indexer;
+pub mod reindexer;
+
+/// Tracks consecutive failures of a daemon so it can sit out ticks while
+/// whatever it depends on (usually the database) recovers.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+    resume_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            failures: 0,
+            resume_at: None,
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        self.resume_at.map_or(true, |at| Instant::now() >= at)
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.resume_at = None;
+    }
+
+    /// Records a failure and returns how long the daemon will wait before trying again.
+    pub fn failed(&mut self) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        let factor = 2i32.saturating_pow(self.failures.min(16) - 1);
+        let delay = std::cmp::min(self.base * factor, self.max);
+
+        self.resume_at = Some(Instant::now() + delay.to_std().unwrap_or_default());
+        delay
+    }
+}
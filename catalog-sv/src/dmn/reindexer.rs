@@ -0,0 +1,245 @@
+use std::sync::Mutex;
+
+use actix_web::rt::time::{Instant, interval_at};
+use actix_web::web;
+use actix_web::web::Data;
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use log::{error, info};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::core::{action, BulkIndexed};
+use crate::core::error::Error;
+use crate::core::error::Error::{IndexQueryPartialError, ReindexConflict};
+use crate::db::{DbConnection, DbConnectionPool};
+use crate::dmn::Backoff;
+use crate::idx::IndexClient;
+
+const BATCH_SIZE: i64 = 500;
+
+/// Movies are copied over in id order, then whatever changed while that was going on
+/// is caught up, then the alias is swapped and anything changed just before the swap
+/// is caught up once more.
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum Phase {
+    Copying { after: Option<Uuid> },
+    CatchingUp,
+    Finishing,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct Rebuild {
+    pub index: String,
+    pub started: DateTime<Utc>,
+    pub copied: usize,
+    #[serde(flatten)]
+    pub phase: Phase,
+    changed_since: DateTime<Utc>,
+    changed_after: Option<Uuid>,
+}
+
+/// Builds a new index from the database alongside the one in use and swaps the alias
+/// over to it once it's caught up.
+///
+/// Nothing is kept across restarts, a rebuild that was going on has to be started again.
+/// The lock is never held while a step is waiting on the database or the index.
+pub struct ReindexDaemon {
+    rebuild: Option<Rebuild>,
+    /// Held while a rebuild's index is being created, so nothing else creates one.
+    reserved: bool,
+    backoff: Backoff,
+}
+
+impl ReindexDaemon {
+    fn new(every: Duration) -> Self {
+        ReindexDaemon {
+            rebuild: None,
+            reserved: false,
+            backoff: Backoff::new(every, Duration::minutes(5)),
+        }
+    }
+
+    pub fn status(&self) -> Option<Rebuild> {
+        self.rebuild.clone()
+    }
+
+    /// Claims the rebuild before its index is created, `begin` or `release` once it is or isn't.
+    pub fn reserve(&mut self) -> Result<(), Error> {
+        if let Some(r) = &self.rebuild {
+            return Err(ReindexConflict(format!("already rebuilding {}", r.index)))
+        }
+        if self.reserved {
+            return Err(ReindexConflict("already starting a rebuild".to_string()))
+        }
+
+        self.reserved = true;
+        Ok(())
+    }
+
+    pub fn release(&mut self) {
+        self.reserved = false;
+    }
+
+    pub fn begin(&mut self, index: String) -> Result<Rebuild, Error> {
+        if let Some(r) = &self.rebuild {
+            return Err(ReindexConflict(format!("already rebuilding {}", r.index)))
+        }
+        self.reserved = false;
+
+        let now = Utc::now();
+        let rebuild = Rebuild {
+            index,
+            started: now,
+            copied: 0,
+            phase: Phase::Copying { after: None },
+            // leeway for clocks that are a bit out between servers
+            changed_since: now - Duration::minutes(1),
+            changed_after: None,
+        };
+
+        info!("starting index rebuild {:?}", rebuild);
+        self.rebuild = Some(rebuild.clone());
+        Ok(rebuild)
+    }
+
+    async fn copy(
+        rebuild: &mut Rebuild,
+        after: Option<Uuid>,
+        pool: Data<DbConnectionPool>,
+        client: Data<IndexClient>,
+    ) -> Result<(), Error> {
+        let conn: DbConnection = pool.get()?;
+
+        let batch = web::block(move || action::find_movies_to_copy(&conn, after, BATCH_SIZE))
+            .await?;
+
+        match batch.last().map(|m| m.id) {
+            None => rebuild.phase = Phase::CatchingUp,
+            Some(last) => {
+                let results = action::index_movies_into(&client, &rebuild.index, batch).await?;
+                let copied = results.indexed.len();
+                Self::give_up_on_rejected(results, pool).await?;
+                rebuild.copied += copied;
+                rebuild.phase = Phase::Copying { after: Some(last) };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Indexes a page of changed movies, `true` once there are none left.
+    async fn catch_up(
+        rebuild: &mut Rebuild,
+        pool: Data<DbConnectionPool>,
+        client: Data<IndexClient>,
+    ) -> Result<bool, Error> {
+        let conn: DbConnection = pool.get()?;
+        let since = rebuild.changed_since;
+        let after = rebuild.changed_after;
+
+        let batch = web::block(move || action::find_movies_changed_since(&conn, since, after, BATCH_SIZE))
+            .await?;
+
+        let caught_up = (batch.len() as i64) < BATCH_SIZE;
+
+        if let Some((since, after)) = batch.last().map(|m| (m.updated, m.id)) {
+            let results = action::index_movies_into(&client, &rebuild.index, batch).await?;
+            Self::give_up_on_rejected(results, pool).await?;
+            rebuild.changed_since = since;
+            rebuild.changed_after = Some(after);
+        }
+
+        Ok(caught_up)
+    }
+
+    /// Movies the index might take later fail the whole batch so it's tried again after
+    /// backing off, the rest are given up on for an operator to retry once it's finished.
+    async fn give_up_on_rejected(results: BulkIndexed, pool: Data<DbConnectionPool>) -> Result<(), Error> {
+        if results.rejected.is_empty() {
+            return Ok(())
+        }
+
+        if results.rejected.iter().any(|r| r.retryable) {
+            return Err(IndexQueryPartialError)
+        }
+
+        let conn: DbConnection = pool.get()?;
+        web::block(move || action::record_index_failures(&conn, results.rejected, false))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Moves a rebuild along a batch, `None` once it's finished.
+    async fn step(
+        mut rebuild: Rebuild,
+        pool: Data<DbConnectionPool>,
+        client: Data<IndexClient>,
+    ) -> Result<Option<Rebuild>, Error> {
+        match rebuild.phase.clone() {
+            Phase::Copying { after } => Self::copy(&mut rebuild, after, pool, client).await?,
+            Phase::CatchingUp => if Self::catch_up(&mut rebuild, pool, client.clone()).await? {
+                action::swap_index_alias(&client, &rebuild.index).await?;
+                rebuild.phase = Phase::Finishing;
+            },
+            Phase::Finishing => if Self::catch_up(&mut rebuild, pool, client).await? {
+                info!("finished index rebuild {:?}", rebuild);
+                return Ok(None)
+            },
+        }
+
+        Ok(Some(rebuild))
+    }
+
+    fn spawn_reindexer(
+        me: Data<Mutex<Self>>,
+        pool: Data<DbConnectionPool>,
+        client: Data<IndexClient>,
+        every: Duration,
+    ) {
+        actix_web::rt::spawn(async move {
+            let mut task = interval_at(
+                Instant::now(),
+                every.to_std().expect("can't spawn on a negative interval"));
+            while task.next().await.is_some() {
+                let rebuild = {
+                    let daemon = me.lock().unwrap();
+                    if !daemon.backoff.ready() {
+                        continue;
+                    }
+                    match daemon.rebuild.clone() {
+                        None => continue,
+                        Some(r) => r,
+                    }
+                };
+
+                // only this task moves a rebuild along, so it's still the one in the daemon
+                let stepped = Self::step(rebuild, pool.clone(), client.clone()).await;
+
+                let mut daemon = me.lock().unwrap();
+                match stepped {
+                    Ok(rebuild) => {
+                        daemon.rebuild = rebuild;
+                        daemon.backoff.succeeded()
+                    }
+                    Err(err) => {
+                        let delay = daemon.backoff.failed();
+                        error!("error rebuilding index, backing off for {}s, {:?}", delay.num_seconds(), err);
+                    }
+                } // continue on after errors
+            }
+        })
+    }
+
+    pub fn start(
+        pool: Data<DbConnectionPool>,
+        client: Data<IndexClient>,
+        every: Duration,
+    ) -> Data<Mutex<Self>> {
+        let me = Data::new(Mutex::new(ReindexDaemon::new(every)));
+        Self::spawn_reindexer(me.clone(), pool.clone(), client.clone(), every);
+        me
+    }
+}
@@ -0,0 +1,85 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::core::{Country, Genre, Language, Movie};
+
+/// A movie as it's indexed, named for the fields in `schema::schema()` rather than the api.
+///
+/// Deleted movies aren't indexed, so there's no `deleted`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MovieDocument {
+    pub id: Uuid,
+    pub title: String,
+    pub tagline: Option<String>,
+    pub overview: Option<String>,
+    pub spoken_language: Vec<Language>,
+    pub production_country: Vec<Country>,
+    pub genre: Vec<Genre>,
+    pub release_date: Option<NaiveDate>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub indexed: Option<DateTime<Utc>>,
+    pub foreign_url: Option<String>,
+}
+
+impl MovieDocument {
+    /// A document with every field filled in, to check the mapping against.
+    pub fn sample() -> Self {
+        let at = Utc.timestamp(0, 0);
+
+        MovieDocument {
+            id: Uuid::nil(),
+            title: String::new(),
+            tagline: Some(String::new()),
+            overview: Some(String::new()),
+            spoken_language: vec![Language { code: String::new(), name: String::new() }],
+            production_country: vec![Country { code: String::new(), name: String::new() }],
+            genre: vec![Genre { id: Uuid::nil(), name: String::new() }],
+            release_date: Some(at.naive_utc().date()),
+            created: at,
+            updated: at,
+            indexed: Some(at),
+            foreign_url: Some(String::new()),
+        }
+    }
+}
+
+impl From<&Movie> for MovieDocument {
+    fn from(m: &Movie) -> Self {
+        MovieDocument {
+            id: m.id,
+            title: m.title.clone(),
+            tagline: m.tagline.clone(),
+            overview: m.overview.clone(),
+            spoken_language: m.spoken_languages.clone(),
+            production_country: m.production_countries.clone(),
+            genre: m.genres.clone(),
+            release_date: m.release_date,
+            created: m.created,
+            updated: m.updated,
+            indexed: m.indexed,
+            foreign_url: m.foreign_url.clone(),
+        }
+    }
+}
+
+impl From<MovieDocument> for Movie {
+    fn from(d: MovieDocument) -> Self {
+        Movie {
+            id: d.id,
+            title: d.title,
+            tagline: d.tagline,
+            overview: d.overview,
+            spoken_languages: d.spoken_language,
+            production_countries: d.production_country,
+            genres: d.genre,
+            release_date: d.release_date,
+            created: d.created,
+            updated: d.updated,
+            indexed: d.indexed,
+            foreign_url: d.foreign_url,
+            deleted: None,
+        }
+    }
+}
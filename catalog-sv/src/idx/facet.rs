@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::core::{DecadeCount, FacetCount, Facets, MovieFilter};
+use crate::idx::query::any_term;
+
+const FACET_SIZE: i64 = 20;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Facet {
+    Genre,
+    Language,
+    Country,
+    Decade,
+}
+
+const FACETS: [Facet; 4] = [Facet::Genre, Facet::Language, Facet::Country, Facet::Decade];
+
+impl Facet {
+    fn name(self) -> &'static str {
+        match self {
+            Facet::Genre => "genre",
+            Facet::Language => "language",
+            Facet::Country => "country",
+            Facet::Decade => "decade",
+        }
+    }
+
+    fn aggregation(self) -> Value {
+        match self {
+            Facet::Genre => json!({ "terms": { "field": "genre.name.raw", "size": FACET_SIZE } }),
+            Facet::Language => json!({ "terms": { "field": "spoken_language.code", "size": FACET_SIZE } }),
+            Facet::Country => json!({ "terms": { "field": "production_country.code", "size": FACET_SIZE } }),
+            // there's no decade interval, years are folded into decades once they're back
+            Facet::Decade => json!({
+                "date_histogram": {
+                    "field": "release_date",
+                    "calendar_interval": "year",
+                    "format": "yyyy",
+                    "min_doc_count": 1
+                }
+            }),
+        }
+    }
+}
+
+/// The filter for each facet that has a selection, facet values match ignoring case
+/// like they do when listing.
+fn selections(filter: &MovieFilter) -> Vec<(Facet, Value)> {
+    let mut selected = Vec::new();
+
+    if !filter.genres().is_empty() {
+        selected.push((Facet::Genre, any_term("genre.name.raw", &filter.genres())));
+    }
+    if !filter.languages().is_empty() {
+        selected.push((Facet::Language, any_term("spoken_language.code", &filter.languages())));
+    }
+    if !filter.countries().is_empty() {
+        selected.push((Facet::Country, any_term("production_country.code", &filter.countries())));
+    }
+    if filter.released_after.is_some() || filter.released_before.is_some() {
+        let mut range = serde_json::Map::new();
+        if let Some(after) = filter.released_after {
+            range.insert("gte".to_string(), json!(after.format("%Y-%m-%d").to_string()));
+        }
+        if let Some(before) = filter.released_before {
+            range.insert("lte".to_string(), json!(before.format("%Y-%m-%d").to_string()));
+        }
+        selected.push((Facet::Decade, json!({ "range": { "release_date": range } })));
+    }
+
+    selected
+}
+
+/// Every selection, for narrowing the hits.
+pub fn post_filter(filter: &MovieFilter) -> Value {
+    let all: Vec<Value> = selections(filter).into_iter()
+        .map(|(_, f)| f)
+        .collect();
+
+    json!({ "bool": { "filter": all } })
+}
+
+/// Counts for every facet, filtered by the selections on all the other facets.
+pub fn aggregations(filter: &MovieFilter) -> Value {
+    let selected = selections(filter);
+
+    let aggs: serde_json::Map<String, Value> = FACETS.iter()
+        .map(|&facet| {
+            let others: Vec<&Value> = selected.iter()
+                .filter(|(f, _)| *f != facet)
+                .map(|(_, v)| v)
+                .collect();
+
+            (facet.name().to_string(), json!({
+                "filter": { "bool": { "filter": others } },
+                "aggs": { "values": facet.aggregation() }
+            }))
+        })
+        .collect();
+
+    Value::Object(aggs)
+}
+
+fn buckets(aggs: &Value, facet: Facet) -> Vec<Value> {
+    aggs[facet.name()]["values"]["buckets"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn counts(aggs: &Value, facet: Facet) -> Vec<FacetCount> {
+    buckets(aggs, facet).iter()
+        .filter_map(|b| Some(FacetCount {
+            value: b["key"].as_str()?.to_string(),
+            count: b["doc_count"].as_i64()?,
+        }))
+        .collect()
+}
+
+fn decades(aggs: &Value) -> Vec<DecadeCount> {
+    let mut by_decade: BTreeMap<i32, i64> = BTreeMap::new();
+
+    buckets(aggs, Facet::Decade).iter()
+        .filter_map(|b| Some((b["key_as_string"].as_str()?.parse::<i32>().ok()?, b["doc_count"].as_i64()?)))
+        .for_each(|(year, count)| *by_decade.entry(year - year.rem_euclid(10)).or_insert(0) += count);
+
+    by_decade.into_iter()
+        .map(|(decade, count)| DecadeCount { decade, count })
+        .collect()
+}
+
+/// Reads the facet counts out of a search response's `aggregations`.
+pub fn parse(aggs: &Value) -> Facets {
+    Facets {
+        genre: counts(aggs, Facet::Genre),
+        language: counts(aggs, Facet::Language),
+        country: counts(aggs, Facet::Country),
+        decade: decades(aggs),
+    }
+}
@@ -1,80 +1,513 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
 use base64::URL_SAFE_NO_PAD;
-use elasticsearch::{BulkParts, Elasticsearch, SearchParts};
+use chrono::{DateTime, Duration, Utc};
+use elasticsearch::{BulkParts, Elasticsearch, OpenPointInTimeParts, SearchParts};
+use elasticsearch::http::StatusCode;
 use elasticsearch::http::request::JsonBody;
-use elasticsearch::indices::{IndicesCreateParts, IndicesExistsParts};
-use log::debug;
+use elasticsearch::indices::{IndicesCreateParts, IndicesDeleteParts, IndicesExistsParts, IndicesGetAliasParts, IndicesGetParts, IndicesGetMappingParts, IndicesPutMappingParts};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use uuid::Uuid;
 
-use crate::core::{Movie, Page, HasId};
+use crate::core::{BulkIndexed, IndexRejection, Movie, Page, HasId, SearchQuery, SearchResults, SearchSort, TitleSuggestion};
 use crate::core::error::Error;
-use crate::core::error::Error::{AnchorDecodeError, AnchorParseError, IndexQueryError, IndexQueryPartialError, SerdeJsonError};
+use crate::core::error::Error::{AnchorDecodeError, AnchorExpired, AnchorParseError, IndexMappingError, IndexQueryError, IndexQueryPartialError, SerdeJsonError, ValidationError};
 use either::Either;
 use either::Either::{Right, Left};
+use crate::idx::document::MovieDocument;
+use crate::idx::schema::MappingDifference;
 
+mod document;
+mod facet;
+mod query;
 mod schema;
 
 pub type IndexClient = Elasticsearch;
 
-pub async fn create_index(client: &IndexClient) -> Result<bool, Error> {
-    let exists = client.indices()
-        .exists(IndicesExistsParts::Index(&[schema::INDEX_NAME]))
+/// The index behind the alias, `None` when there's no alias, which could mean
+/// there's an unversioned index of the same name.
+pub async fn alias_target(client: &IndexClient) -> Result<Option<String>, Error> {
+    let response = client.indices()
+        .get_alias(IndicesGetAliasParts::Name(&[schema::INDEX_NAME]))
+        .send()
+        .await?;
+
+    if response.status_code() == StatusCode::NOT_FOUND {
+        return Ok(None)
+    }
+
+    let body: Value = response
+        .error_for_status_code()
+        .map_err(IndexQueryError)?
+        .json()
+        .await
+        .map_err(IndexQueryError)?;
+
+    Ok(body.as_object().and_then(|indices| indices.keys().next().cloned()))
+}
+
+pub async fn index_exists(client: &IndexClient, index: &str) -> Result<bool, Error> {
+    let response = client.indices()
+        .exists(IndicesExistsParts::Index(&[index]))
+        .send()
+        .await?;
+
+    Ok(response.status_code().is_success())
+}
+
+pub fn versioned_index_name() -> String {
+    schema::versioned_index_name(schema::INDEX_VERSION)
+}
+
+/// Whether `index` is of the current version, an index can be rebuilt at the same
+/// version, the rebuild's name has a suffix on the version's name.
+pub fn is_current_version(index: &str) -> bool {
+    let current = versioned_index_name();
+    index == current || index.starts_with(&format!("{}_", current))
+}
+
+/// Every versioned index, of this version or an older one, whether or not the alias is on it.
+pub async fn versioned_indices(client: &IndexClient) -> Result<Vec<String>, Error> {
+    let pattern = format!("{}_v*", schema::INDEX_NAME);
+
+    let response: Value = client.indices()
+        .get(IndicesGetParts::Index(&[pattern.as_str()]))
+        .send()
+        .await?
+        .error_for_status_code()
+        .map_err(IndexQueryError)?
+        .json()
+        .await
+        .map_err(IndexQueryError)?;
+
+    Ok(response.as_object()
+        .map(|indices| indices.keys().cloned().collect())
+        .unwrap_or_default())
+}
+
+/// Creates an index with the current mapping, with the alias when `aliased`.
+pub async fn create_versioned_index(client: &IndexClient, index: &str, aliased: bool) -> Result<(), Error> {
+    let mut body = schema::schema();
+    if aliased {
+        body["aliases"][schema::INDEX_NAME] = json!({});
+    }
+
+    client.indices()
+        .create(IndicesCreateParts::Index(index))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()
+        .map(|_| ())
+        .map_err(IndexQueryError)
+}
+
+pub async fn delete_index(client: &IndexClient, index: &str) -> Result<(), Error> {
+    client.indices()
+        .delete(IndicesDeleteParts::Index(&[index]))
+        .send()
+        .await?
+        .error_for_status_code()
+        .map(|_| ())
+        .map_err(IndexQueryError)
+}
+
+/// Points the alias at `index` and away from whatever it was on, in one step so
+/// nothing reads or writes in between. An unversioned index in the alias's way is
+/// removed in the same step.
+pub async fn swap_alias(client: &IndexClient, index: &str) -> Result<Option<String>, Error> {
+    let previous = alias_target(client).await?;
+
+    let mut actions = Vec::new();
+    match &previous {
+        Some(old) => actions.push(json!({ "remove": { "index": old, "alias": schema::INDEX_NAME } })),
+        None => if index_exists(client, schema::INDEX_NAME).await? {
+            actions.push(json!({ "remove_index": { "index": schema::INDEX_NAME } }))
+        },
+    }
+    actions.push(json!({ "add": { "index": index, "alias": schema::INDEX_NAME } }));
+
+    client.indices()
+        .update_aliases()
+        .body(json!({ "actions": actions }))
+        .send()
+        .await?
+        .error_for_status_code()
+        .map_err(IndexQueryError)?;
+
+    Ok(previous)
+}
+
+/// What to do when the index's mapping isn't the one the service expects.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MappingDrift {
+    /// Log the differences and carry on with the index as it is.
+    Log,
+    /// Refuse to start.
+    Fail,
+    /// Rebuild into a new index with the expected mapping.
+    Migrate,
+}
+
+impl FromStr for MappingDrift {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(MappingDrift::Log),
+            "fail" => Ok(MappingDrift::Fail),
+            "migrate" => Ok(MappingDrift::Migrate),
+            _ => Err(format!("{} isn't log, fail or migrate", s)),
+        }
+    }
+}
+
+/// How the index in use compares to the one the service expects.
+#[derive(Clone, Debug)]
+pub enum IndexState {
+    Current,
+    /// The current version, with a mapping that's been changed in a way that
+    /// can't be made up for in place.
+    Drifted(String, Vec<MappingDifference>),
+    /// An older version, or the unversioned index.
+    Outdated(String),
+}
+
+async fn live_mappings(client: &IndexClient, index: &str) -> Result<Value, Error> {
+    let response: Value = client.indices()
+        .get_mapping(IndicesGetMappingParts::Index(&[index]))
         .send()
         .await?
-        .error_for_status_code();
+        .error_for_status_code()
+        .map_err(IndexQueryError)?
+        .json()
+        .await
+        .map_err(IndexQueryError)?;
+
+    Ok(response[index]["mappings"].clone())
+}
 
-    match exists {
-        Ok(_) => Ok(true),
-        Err(_) => client.indices()
-            .create(IndicesCreateParts::Index(schema::INDEX_NAME))
-            .body(schema::schema())
-            .send()
-            .await?
-            .error_for_status_code()
-            .map(|_| true)
-            .map_err(IndexQueryError)
+/// Makes sure there's an index to use and says how it compares to the expected one.
+///
+/// A new index is created behind the alias, fields missing from an existing one
+/// are added in place, anything else is left to the caller.
+pub async fn create_index(client: &IndexClient) -> Result<IndexState, Error> {
+    let differences = schema::differences(&serde_json::to_value(MovieDocument::sample())?);
+    if !differences.is_empty() {
+        return Err(IndexMappingError(differences.join(", ")))
     }
+
+    let target = alias_target(client).await?;
+    let unversioned = target.is_none() && index_exists(client, schema::INDEX_NAME).await?;
+
+    let index = match target {
+        Some(index) if is_current_version(&index) => index,
+        Some(index) => return Ok(IndexState::Outdated(index)),
+        None if unversioned => return Ok(IndexState::Outdated(schema::INDEX_NAME.to_string())),
+        None => {
+            create_versioned_index(client, &versioned_index_name(), true).await?;
+            return Ok(IndexState::Current)
+        }
+    };
+
+    let drift = schema::drift(&live_mappings(client, &index).await?);
+
+    if drift.is_empty() {
+        return Ok(IndexState::Current)
+    }
+
+    if !drift.iter().all(MappingDifference::is_addition) {
+        return Ok(IndexState::Drifted(index, drift))
+    }
+
+    info!("adding fields to index mapping {:?}", drift.iter().map(|d| &d.path).collect::<Vec<_>>());
+
+    client.indices()
+        .put_mapping(IndicesPutMappingParts::Index(&[index.as_str()]))
+        .body(schema::schema()["mappings"].clone())
+        .send()
+        .await?
+        .error_for_status_code()
+        .map(|_| IndexState::Current)
+        .map_err(IndexQueryError)
+}
+
+/// Indexes movies into the index behind the alias.
+pub async fn index_movies(client: &IndexClient, movies: Vec<Movie>) -> Result<BulkIndexed, Error> {
+    index_movies_into(client, schema::INDEX_NAME, movies).await
 }
 
-pub async fn index_movies(client: &IndexClient, movies: Vec<Movie>) -> Result<Vec<Movie>, Error> {
+/// Indexes movies into `index`, deleted ones are taken out of it.
+///
+/// Only failing to send the request at all is an error, movies the index turns down
+/// one by one come back as rejected.
+pub async fn index_movies_into(client: &IndexClient, index: &str, movies: Vec<Movie>) -> Result<BulkIndexed, Error> {
     if movies.is_empty() {
-        return Ok(Vec::new())
+        return Ok(BulkIndexed::default())
     }
 
     let mut body: Vec<JsonBody<_>> = Vec::with_capacity(movies.len() * 2);
 
     movies.iter().try_for_each(|m: &Movie| {
+        let action = json!({"_id": m.id.clone(), "version": version(m), "version_type": "external_gte"});
         match m.deleted {
-            None => serde_json::to_value(m).map(|json| {
-                body.push(json!({"index": {"_id": m.id.clone()}}).into());
+            None => serde_json::to_value(MovieDocument::from(m)).map(|json| {
+                body.push(json!({"index": action}).into());
                 body.push(JsonBody::new(json))
             }),
             Some(_) => {
-                body.push(json!({"delete": {"_id": m.id.clone()}}).into());
+                body.push(json!({"delete": action}).into());
                 Ok(())
             }
         }
 
     }).map_err(SerdeJsonError)?;
 
-    let response = client
-        .bulk(BulkParts::Index(schema::INDEX_NAME))
+    let response_body = client
+        .bulk(BulkParts::Index(index))
         .body(body)
         .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
         .await?;
 
-    let response_body = response.json::<Value>().await?;
-    let successful = response_body["errors"].as_bool().unwrap() == false;
+    let items = response_body["items"].as_array()
+        .filter(|items| items.len() == movies.len())
+        .ok_or(IndexQueryPartialError)?
+        .clone();
 
-    if successful {
-        Ok(movies)
-    } else {
-        Err(IndexQueryPartialError)
+    let mut results = BulkIndexed::default();
+
+    // items come back in the order they were sent, one per movie
+    for (movie, item) in movies.into_iter().zip(items) {
+        match bulk_item_rejection(&item) {
+            None => results.indexed.push(movie),
+            Some((reason, retryable)) => results.rejected.push(IndexRejection { movie, reason, retryable }),
+        }
     }
+
+    Ok(results)
+}
+
+/// The version a movie is indexed at, so an older read of it can't overwrite a newer one
+/// that got there first. Relies on `updated` only moving forwards between edits.
+fn version(movie: &Movie) -> i64 {
+    movie.updated.timestamp() * 1_000_000 + movie.updated.timestamp_subsec_micros() as i64
+}
+
+/// Why a bulk item failed and whether it's worth trying again, `None` if it didn't.
+fn bulk_item_rejection(item: &Value) -> Option<(String, bool)> {
+    let result = item.as_object()
+        .and_then(|o| o.values().next())
+        .unwrap_or(&Value::Null);
+
+    let status = result["status"].as_u64().unwrap_or(0);
+    let error = &result["error"];
+
+    // deleting a movie that never made it into the index is fine
+    if error.is_null() && (status < 300 || status == 404) {
+        return None
+    }
+
+    // the index already has this version or a newer one
+    if status == 409 && error["type"].as_str() == Some("version_conflict_engine_exception") {
+        return None
+    }
+
+    let reason = match (error["type"].as_str(), error["reason"].as_str()) {
+        (Some(t), Some(r)) => format!("{}: {}", t, r),
+        (Some(t), None) => t.to_string(),
+        _ => format!("status {}", status),
+    };
+
+    // too many requests or something wrong with the cluster, anything else is the document
+    let retryable = status == 429 || status >= 500 || status == 0;
+
+    Some((reason, retryable))
 }
 
+/// How many more completions to ask for than are returned, so ranking them picks from more
+/// than whichever came back first.
+const SUGGEST_CANDIDATES: i64 = 5;
+
+/// Titles starting with `prefix`, best first.
+///
+/// Nothing's weighted so the index scores every completion the same, they're ranked
+/// here instead, shortest title first since it's the closest to what's been typed,
+/// then newest first. Movies with the same title are all kept, the year tells them apart.
+pub async fn suggest_titles(client: &IndexClient, prefix: &str, size: i64) -> Result<Vec<TitleSuggestion>, Error> {
+    let query = json!({
+        "size": 0,
+        "_source": [ "id", "title", "release_date" ],
+        "suggest": {
+            "titles": {
+                "prefix": prefix,
+                "completion": {
+                    "field": "title.suggest",
+                    "size": size * SUGGEST_CANDIDATES
+                }
+            }
+        }
+    });
+
+    debug!("{}", query);
+
+    let response: Value = client
+        .search(SearchParts::Index(&[schema::INDEX_NAME]))
+        .body(query)
+        .send()
+        .await?
+        .error_for_status_code()
+        .map_err(IndexQueryError)?
+        .json()
+        .await
+        .map_err(IndexQueryError)?;
+
+    debug!("{}", response);
+
+    let mut suggestions: Vec<TitleSuggestion> = response["suggest"]["titles"][0]["options"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|option| {
+            let source = &option["_source"];
+            Some(TitleSuggestion {
+                id: source["id"].as_str()?.parse().ok()?,
+                title: source["title"].as_str()?.to_string(),
+                year: source["release_date"].as_str()
+                    .and_then(|date| date.get(..4))
+                    .and_then(|year| year.parse().ok()),
+            })
+        })
+        .collect();
+
+    suggestions.sort_by_key(|s| (s.title.chars().count(), Reverse(s.year)));
+    suggestions.truncate(size as usize);
+
+    Ok(suggestions)
+}
+
+/// The movie a hit is for, or just its id when the document can't be read as one.
+fn hit_movie(hit: &Value) -> Either<HasId, Movie> {
+    serde_json::from_value::<MovieDocument>(hit["_source"].clone())
+        .map(|d| Right(Movie::from(d)))
+        .or_else(|_|
+            serde_json::from_value::<HasId>(hit["_source"].clone())
+                .map(Left)
+        )
+        .unwrap()
+}
+
+/// Movies like the one with `id`, by the words in their overview and tagline and their genres.
+///
+/// Only ids come back, the movies are all left to be read from the database.
+pub async fn find_related_movies(client: &IndexClient, id: Uuid, count: i64) -> Result<Page<Either<HasId, Movie>>, Error> {
+    let query = json!({
+        "_source": [ "id" ],
+        "query": {
+            "more_like_this": {
+                "fields": [ "overview", "tagline", "genre.name" ],
+                "like": [ { "_index": schema::INDEX_NAME, "_id": id } ],
+                "min_term_freq": 1,
+                "min_doc_freq": 2,
+                "max_query_terms": 25
+            }
+        },
+        "size": count,
+    });
+
+    debug!("{}", query);
+
+    let response: Value = client
+        .search(SearchParts::Index(&[schema::INDEX_NAME]))
+        .body(query)
+        .send()
+        .await?
+        .error_for_status_code()
+        .map_err(IndexQueryError)?
+        .json()
+        .await
+        .map_err(IndexQueryError)?;
+
+    debug!("{}", response);
+
+    let items = response["hits"]["hits"]
+        .as_array()
+        .map(|hits| hits.iter().map(hit_movie).collect())
+        .unwrap_or_default();
+
+    Ok(Page {
+        page_number: 1,
+        next_anchor: None,
+        items,
+    })
+}
+
+/// The points in time open for paging searches, so searches that are given up on after
+/// the first page can't use up the cluster's search contexts.
+///
+/// Each server keeps count of its own, they're forgotten once they'd have expired.
+pub struct SearchContexts {
+    keep_alive: Duration,
+    max_open: usize,
+    open: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl SearchContexts {
+    pub fn new(keep_alive: Duration, max_open: usize) -> Self {
+        SearchContexts {
+            keep_alive,
+            max_open,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn keep_alive(&self) -> String {
+        format!("{}s", self.keep_alive.num_seconds())
+    }
+
+    /// Whether there's room for another, the cap can be overshot by searches starting together.
+    fn has_room(&self) -> bool {
+        let mut open = self.open.lock().unwrap();
+        let now = Utc::now();
+        open.retain(|_, expires| *expires > now);
+        open.len() < self.max_open
+    }
+
+    fn opened(&self, pit: &str) {
+        let mut open = self.open.lock().unwrap();
+        open.insert(pit.to_string(), Utc::now() + self.keep_alive);
+        debug!("opened point in time, {} open", open.len());
+    }
+
+    fn renewed(&self, previous: &str, pit: &str) {
+        let mut open = self.open.lock().unwrap();
+        open.remove(previous);
+        open.insert(pit.to_string(), Utc::now() + self.keep_alive);
+    }
+
+    fn closed(&self, pit: &str) {
+        self.open.lock().unwrap().remove(pit);
+    }
+}
+
+/// Where a page of search results ended, in the point in time the search started
+/// with, so later pages aren't moved around by indexing.
+///
+/// Only works for the sort it was made for.
+/// Pages follow on in the live index when there was no room for a point in time.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct MovieAnchor {
+    sort: SearchSort,
+    pit: Option<String>,
+    search_after: Vec<Value>,
     page_number: i64,
 }
 
@@ -91,39 +524,119 @@ fn serialize_anchor(anch: MovieAnchor) -> String {
     base64::encode_config(d, URL_SAFE_NO_PAD)
 }
 
+/// A point in time to page a new search through, `None` if too many are open already.
+async fn open_pit(client: &IndexClient, contexts: &SearchContexts) -> Result<Option<String>, Error> {
+    if !contexts.has_room() {
+        warn!("too many points in time open, searching without one");
+        return Ok(None)
+    }
+
+    let response: Value = client
+        .open_point_in_time(OpenPointInTimeParts::Index(&[schema::INDEX_NAME]))
+        .keep_alive(&contexts.keep_alive())
+        .send()
+        .await?
+        .error_for_status_code()
+        .map_err(IndexQueryError)?
+        .json()
+        .await
+        .map_err(IndexQueryError)?;
+
+    let pit = response["id"].as_str()
+        .map(|id| id.to_string())
+        .ok_or(IndexQueryPartialError)?;
+
+    contexts.opened(&pit);
+    Ok(Some(pit))
+}
+
+// it expires on its own anyway, closing is only to free it up sooner
+async fn close_pit(client: &IndexClient, contexts: &SearchContexts, pit: String) {
+    contexts.closed(&pit);
+
+    let closed = client
+        .close_point_in_time()
+        .body(json!({ "id": pit }))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status_code());
+
+    if let Err(e) = closed {
+        debug!("error closing point in time {:?}", e);
+    }
+}
+
 pub async fn search_movies(
     client: &IndexClient,
-    search_term: &String,
+    contexts: &SearchContexts,
+    search: &SearchQuery,
     count: i64,
     anchor: &Option<String>
-) -> Result<Page<Either<HasId, Movie>>, Error> {
-    let this_page = match anchor {
-        None => 1,
-        Some(anch) => deserialize_anchor(anch.to_string())?.page_number
+) -> Result<SearchResults, Error> {
+    let anch = match anchor {
+        None => None,
+        Some(a) => Some(deserialize_anchor(a.to_string())?),
     };
 
-    let from = (this_page - 1) * count;
-    
-    let query = json!({
-            "query": {
-                "simple_query_string": {
-                    "query": format!("\"{}\"", search_term),
-                    "fields": [ "title" ],
-                }
-            }
+    let (sort, pit, search_after, this_page) = match (anch, search.sort) {
+        (None, s) => (s.unwrap_or_default(), open_pit(client, contexts).await?, None, 1),
+        (Some(a), Some(s)) if a.sort != s =>
+            return Err(ValidationError(format!("anchor is for sort {}, not {}", a.sort, s))),
+        (Some(a), _) => (a.sort, a.pit, Some(a.search_after), a.page_number),
+    };
+
+    // the point in time stands in for the index, and pages by search after, one extra
+    // hit tells whether there's another page
+    let mut query = json!({
+            "query": query::relevance(search),
+            "post_filter": facet::post_filter(&search.filter),
+            "sort": query::sort(sort),
+            "size": count + 1,
         });
+
+    match &pit {
+        Some(id) => query["pit"] = json!({ "id": id, "keep_alive": contexts.keep_alive() }),
+        // without one there's no tiebreaker added, so pages need one of their own
+        None => if let Some(sorts) = query["sort"].as_array_mut() {
+            sorts.push(json!({ "id": "asc" }));
+        },
+    }
+
+    if let Some(after) = search_after {
+        query["search_after"] = Value::Array(after);
+    }
+
+    if search.facets {
+        query["aggs"] = facet::aggregations(&search.filter);
+    }
+
+    if search.highlight {
+        query["highlight"] = query::highlight();
+    }
+
+    // there's nothing to correct when only qualifiers were typed
+    if !search.term.trim().is_empty() {
+        query["suggest"] = query::suggest(search);
+    }
     
     debug!("{}", query);
 
+    let parts = match pit {
+        Some(_) => SearchParts::None,
+        None => SearchParts::Index(&[schema::INDEX_NAME]),
+    };
+    let paged_in_time = pit.is_some() && this_page > 1;
+
     let response: Value = client
-        .search(SearchParts::Index(&[schema::INDEX_NAME]))
-        .from(from)
-        .size(count)
+        .search(parts)
         .body(query)
         .send()
         .await?
         .error_for_status_code()
-        .map_err(IndexQueryError)?
+        .map_err(|e| match e.status_code() {
+            Some(StatusCode::NOT_FOUND) if paged_in_time => AnchorExpired,
+            _ => IndexQueryError(e),
+        })?
         .json()
         .await
         .map_err(IndexQueryError)?;
@@ -134,34 +647,66 @@ pub async fn search_movies(
         .as_i64()
         .unwrap_or(0);
 
-    let remaining_count = total_count - this_page * count;
-
-    let items: Vec<Either<HasId, Movie>> = response["hits"]["hits"]
+    let mut hits = response["hits"]["hits"]
         .as_array()
-        .unwrap()
-        .iter()
-        .map(|hit|
-            serde_json::from_value::<Movie>(hit["_source"].clone())
-                .map(|m| Right(m))
-                .or_else(|_|
-                    serde_json::from_value::<HasId>(hit["_source"].clone())
-                        .map(|id| Left(id))
-                )
-                .unwrap()
-        )
-        .collect();
+        .cloned()
+        .unwrap_or_default();
 
-    let next_anchor = if remaining_count > 0 {
-        Some(MovieAnchor {
-            page_number: this_page + 1
-        })
+    let has_more = hits.len() as i64 > count;
+    hits.truncate(count as usize);
+
+    let items: Vec<Either<HasId, Movie>> = hits.iter().map(hit_movie).collect();
+
+    // the point in time's id can change from one search to the next
+    let pit = pit.map(|previous| {
+        let renewed = response["pit_id"].as_str()
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| previous.clone());
+        contexts.renewed(&previous, &renewed);
+        renewed
+    });
+
+    let next_anchor = match hits.last() {
+        Some(last) if has_more => Some(MovieAnchor {
+            sort,
+            pit,
+            // sort values end with a tiebreaker, the shard doc a point in time adds on its own
+            // or the id
+            search_after: last["sort"].as_array().cloned().unwrap_or_default(),
+            page_number: this_page + 1,
+        }),
+        _ => {
+            if let Some(p) = pit {
+                close_pit(client, contexts, p).await;
+            }
+            None
+        }
+    };
+
+    let did_you_mean = query::did_you_mean(search, total_count, &response["suggest"]);
+
+    let matches = if search.highlight {
+        Some(hits.iter()
+            .filter_map(|hit| Some((hit["_id"].as_str()?.parse().ok()?, query::search_match(hit))))
+            .collect())
+    } else {
+        None
+    };
+
+    let facets = if search.facets {
+        Some(facet::parse(&response["aggregations"]))
     } else {
         None
     };
     
-    Ok(Page {
-        page_number: this_page,
-        next_anchor: next_anchor.map(serialize_anchor),
-        items,
+    Ok(SearchResults {
+        page: Page {
+            page_number: this_page,
+            next_anchor: next_anchor.map(serialize_anchor),
+            items,
+        },
+        facets,
+        did_you_mean,
+        matches,
     })
-}
\ No newline at end of file
+}
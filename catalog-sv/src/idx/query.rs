@@ -0,0 +1,214 @@
+use serde_json::{json, Value};
+
+use crate::core::{Fuzziness, MatchMode, SearchMatch, SearchQuery, SearchSort};
+use crate::core::query::Qualifier;
+
+/// Title matches count for more than tagline matches, which count for more than overview ones.
+const FIELDS: [&str; 3] = ["title.english^3", "tagline.english^2", "overview.english"];
+
+/// Searches finding this many movies or fewer get a "did you mean".
+const SUGGEST_AT_MOST: i64 = 3;
+
+fn fuzziness_value(fuzziness: Fuzziness) -> Value {
+    match fuzziness {
+        Fuzziness::Auto => json!("AUTO"),
+        Fuzziness::Edits(n) => json!(n),
+    }
+}
+
+fn exact(search: &SearchQuery) -> Value {
+    match search.mode {
+        MatchMode::Phrase => json!({
+            "multi_match": {
+                "query": search.term,
+                "type": "phrase",
+                "fields": FIELDS,
+            }
+        }),
+        // cross fields so the terms can be spread over title, tagline and overview
+        MatchMode::All => json!({
+            "multi_match": {
+                "query": search.term,
+                "type": "cross_fields",
+                "operator": "and",
+                "fields": FIELDS,
+            }
+        }),
+        MatchMode::Any => json!({
+            "multi_match": {
+                "query": search.term,
+                "type": "best_fields",
+                "operator": "or",
+                "fields": FIELDS,
+            }
+        }),
+    }
+}
+
+fn fuzzy_match(term: &str, fuzziness: Fuzziness) -> Value {
+    json!({
+        "multi_match": {
+            "query": term,
+            "type": "best_fields",
+            "fuzziness": fuzziness_value(fuzziness),
+            "fields": FIELDS,
+        }
+    })
+}
+
+/// Only best fields matching takes a fuzziness, so every term needing to match is
+/// spelled out one term at a time.
+fn fuzzy(search: &SearchQuery, fuzziness: Fuzziness) -> Value {
+    match search.mode {
+        MatchMode::Any => fuzzy_match(&search.term, fuzziness),
+        MatchMode::All | MatchMode::Phrase => {
+            let every_term: Vec<Value> = search.term.split_whitespace()
+                .map(|term| fuzzy_match(term, fuzziness))
+                .collect();
+
+            json!({ "bool": { "must": every_term } })
+        }
+    }
+}
+
+/// Scores movies on how well the search term matches their text.
+///
+/// With a fuzziness, near misses match too, exact matches get a boost on top.
+fn scoring(search: &SearchQuery) -> Value {
+    if search.term.trim().is_empty() {
+        return json!({ "match_all": {} })
+    }
+
+    match search.fuzziness {
+        None | Some(Fuzziness::Edits(0)) => exact(search),
+        Some(fuzziness) => json!({
+            "bool": {
+                "should": [
+                    { "constant_score": { "filter": exact(search), "boost": 10 } },
+                    fuzzy(search, fuzziness),
+                ],
+                "minimum_should_match": 1
+            }
+        }),
+    }
+}
+
+/// Sort values for search after, a point in time adds a tiebreaker on its own.
+pub fn sort(sort: SearchSort) -> Value {
+    match sort {
+        SearchSort::Relevance => json!([ { "_score": "desc" } ]),
+        SearchSort::Title => json!([ { "title.raw": "asc" }, { "_score": "desc" } ]),
+        SearchSort::ReleaseDate => json!([
+            { "release_date": { "order": "asc", "missing": "_last" } },
+            { "_score": "desc" }
+        ]),
+        SearchSort::ReleaseDateDesc => json!([
+            { "release_date": { "order": "desc", "missing": "_last" } },
+            { "_score": "desc" }
+        ]),
+    }
+}
+
+/// Matches any of `values` ignoring case, the way the database filters do.
+pub fn any_term(field: &str, values: &[String]) -> Value {
+    let terms: Vec<Value> = values.iter()
+        .map(|v| json!({ "term": { field: { "value": v, "case_insensitive": true } } }))
+        .collect();
+
+    json!({ "bool": { "should": terms, "minimum_should_match": 1 } })
+}
+
+fn qualifier_filter(qualifier: &Qualifier) -> Value {
+    match qualifier {
+        Qualifier::Genre(names) => any_term("genre.name.raw", names),
+        Qualifier::Language(codes) => any_term("spoken_language.code", codes),
+        Qualifier::Country(codes) => any_term("production_country.code", codes),
+        Qualifier::Year(from, to) => {
+            let mut range = serde_json::Map::new();
+            if let Some(f) = from {
+                range.insert("gte".to_string(), json!(format!("{:04}-01-01", f)));
+            }
+            if let Some(t) = to {
+                range.insert("lte".to_string(), json!(format!("{:04}-12-31", t)));
+            }
+            json!({ "range": { "release_date": range } })
+        }
+    }
+}
+
+/// The search's free text scored, within the qualifiers typed with it.
+///
+/// Unlike facet selections, qualifiers narrow the facet counts too.
+pub fn relevance(search: &SearchQuery) -> Value {
+    let filters: Vec<Value> = search.qualifiers.iter()
+        .map(qualifier_filter)
+        .collect();
+
+    json!({
+        "bool": {
+            "must": scoring(search),
+            "filter": filters,
+        }
+    })
+}
+
+/// A phrase suggester over titles, asked for with every search since whether it's
+/// needed isn't known until the hits come back.
+pub fn suggest(search: &SearchQuery) -> Value {
+    json!({
+        "text": search.term,
+        "did_you_mean": {
+            "phrase": {
+                "field": "title",
+                "size": 1,
+                "gram_size": 1,
+                "direct_generator": [{
+                    "field": "title",
+                    "suggest_mode": "always"
+                }]
+            }
+        }
+    })
+}
+
+/// The corrected term, if the search found little and there's a correction to make.
+pub fn did_you_mean(search: &SearchQuery, total: i64, suggest: &Value) -> Option<String> {
+    if total > SUGGEST_AT_MOST {
+        return None
+    }
+
+    suggest["did_you_mean"][0]["options"][0]["text"]
+        .as_str()
+        .filter(|text| !text.eq_ignore_ascii_case(search.term.trim()))
+        .map(|text| text.to_string())
+}
+
+/// Highlighting on the fields that were searched, so fragments line up with what matched.
+pub fn highlight() -> Value {
+    json!({
+        "pre_tags": [ "<em>" ],
+        "post_tags": [ "</em>" ],
+        "fields": {
+            "title.english": { "number_of_fragments": 0 },
+            "tagline.english": { "number_of_fragments": 0 },
+            "overview.english": { "fragment_size": 150, "number_of_fragments": 3 }
+        }
+    })
+}
+
+/// The highlighted fragments of a hit, keyed by the movie field they're from.
+pub fn search_match(hit: &Value) -> SearchMatch {
+    let highlights = hit["highlight"]
+        .as_object()
+        .map(|fields| fields.iter()
+            .map(|(field, fragments)| (
+                field.trim_end_matches(".english").to_string(),
+                fragments.as_array()
+                    .map(|f| f.iter().filter_map(|s| s.as_str().map(|s| s.to_string())).collect())
+                    .unwrap_or_default(),
+            ))
+            .collect())
+        .unwrap_or_default();
+
+    SearchMatch { highlights }
+}
@@ -1,10 +1,23 @@
-use serde_json::{json, Value};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
 
+/// The alias everything reads and writes through, the unversioned index from before
+/// there were versions was called this too, and counts as version 1.
 pub const INDEX_NAME: &str = "catalog";
 
+/// Bumped for any mapping change that can't be made in place, so movies get
+/// rebuilt into a new index instead.
+pub const INDEX_VERSION: u32 = 2;
+
+pub fn versioned_index_name(version: u32) -> String {
+    format!("{}_v{}", INDEX_NAME, version)
+}
+
 pub fn schema() -> Value {
     json!({
         "mappings" : {
+            // anything not mapped here is a mistake, not a new field
+            "dynamic": "strict",
             "properties" : {
                 "id" : {
                     "type" : "keyword"
@@ -18,6 +31,9 @@ pub fn schema() -> Value {
                         "english": {
                             "type": "text",
                             "analyzer": "english"
+                        },
+                        "suggest": {
+                            "type": "completion"
                         }
                     }
                 },
@@ -96,8 +112,129 @@ pub fn schema() -> Value {
                 },
                 "indexed": {
                     "type": "date"
+                },
+                "foreign_url": {
+                    "type": "keyword",
+                    "index": false
                 }
             }
         }
     })
-}
\ No newline at end of file
+}
+
+fn compare(document: &Value, properties: &Value, path: &str, differences: &mut Vec<String>) {
+    let fields = document.as_object().cloned().unwrap_or_default();
+    let mapped = properties.as_object().cloned().unwrap_or_default();
+
+    for (name, value) in &fields {
+        let field_path = format!("{}{}", path, name);
+        let nested = match value {
+            Value::Array(items) => items.first().cloned().unwrap_or(Value::Null),
+            v => v.clone(),
+        };
+
+        match mapped.get(name) {
+            None => differences.push(format!("{} isn't mapped", field_path)),
+            Some(mapping) if nested.is_object() =>
+                compare(&nested, &mapping["properties"], &format!("{}.", field_path), differences),
+            Some(_) => (),
+        }
+    }
+
+    mapped.keys()
+        .filter(|name| !fields.contains_key(*name))
+        .for_each(|name| differences.push(format!("{}{} isn't in the document", path, name)));
+}
+
+/// Fields of `document` the mapping doesn't have and mapped fields `document` doesn't have,
+/// `document` needs every field filled in, with one item in every array.
+pub fn differences(document: &Value) -> Vec<String> {
+    let mut differences = Vec::new();
+    compare(document, &schema()["mappings"]["properties"], "", &mut differences);
+    differences
+}
+
+/// A field whose live mapping isn't what `schema()` says, `live` is `None` when it's
+/// missing from the index, `expected` is `None` when the index has it but shouldn't.
+#[derive(Clone, Serialize, Debug)]
+pub struct MappingDifference {
+    pub path: String,
+    pub expected: Option<Value>,
+    pub live: Option<Value>,
+}
+
+impl MappingDifference {
+    /// Whether the index can be brought in line by adding to its mapping.
+    pub fn is_addition(&self) -> bool {
+        self.live.is_none()
+    }
+}
+
+// a field's own parameters, without the fields under it
+fn parameters(field: &Value) -> Map<String, Value> {
+    field.as_object()
+        .map(|f| f.iter()
+            .filter(|(k, _)| *k != "properties" && *k != "fields")
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+        .unwrap_or_default()
+}
+
+fn compare_fields(expected: &Value, live: &Value, path: &str, drift: &mut Vec<MappingDifference>) {
+    let expected_fields = expected.as_object().cloned().unwrap_or_default();
+    let live_fields = live.as_object().cloned().unwrap_or_default();
+
+    for (name, expected_field) in &expected_fields {
+        let field_path = format!("{}{}", path, name);
+
+        let live_field = match live_fields.get(name) {
+            None => {
+                drift.push(MappingDifference { path: field_path, expected: Some(expected_field.clone()), live: None });
+                continue;
+            }
+            Some(f) => f,
+        };
+
+        // the index fills in defaults for anything left out, so only what's asked for is compared
+        let expected_parameters = parameters(expected_field);
+        let live_parameters = parameters(live_field);
+        if expected_parameters.iter().any(|(k, v)| live_parameters.get(k) != Some(v)) {
+            drift.push(MappingDifference {
+                path: field_path.clone(),
+                expected: Some(Value::Object(expected_parameters)),
+                live: Some(Value::Object(live_parameters)),
+            });
+        }
+
+        for nested in &["properties", "fields"] {
+            if !expected_field[*nested].is_null() || !live_field[*nested].is_null() {
+                compare_fields(&expected_field[*nested], &live_field[*nested], &format!("{}.", field_path), drift);
+            }
+        }
+    }
+
+    live_fields.iter()
+        .filter(|(name, _)| !expected_fields.contains_key(*name))
+        .for_each(|(name, live_field)| drift.push(MappingDifference {
+            path: format!("{}{}", path, name),
+            expected: None,
+            live: Some(live_field.clone()),
+        }));
+}
+
+/// How the live `mappings` of an index differ from `schema()`.
+pub fn drift(live: &Value) -> Vec<MappingDifference> {
+    let expected = &schema()["mappings"];
+    let mut drift = Vec::new();
+
+    if expected["dynamic"] != live["dynamic"] {
+        drift.push(MappingDifference {
+            path: "dynamic".to_string(),
+            expected: Some(expected["dynamic"].clone()),
+            live: Some(live["dynamic"].clone()),
+        });
+    }
+
+    compare_fields(&expected["properties"], &live["properties"], "", &mut drift);
+    drift
+}
@@ -13,8 +13,10 @@ use elasticsearch::Elasticsearch;
 use elasticsearch::http::transport::{SingleNodeConnectionPool, TransportBuilder};
 use elasticsearch::http::Url;
 use log::info;
+use std::str::FromStr;
 
 use crate::core::action;
+use crate::idx::{MappingDrift, SearchContexts};
 
 mod api;
 mod core;
@@ -22,6 +24,11 @@ mod dmn;
 mod db;
 mod idx;
 
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key).map_or(default, |v| v.parse()
+        .unwrap_or_else(|_| panic!("{} was invalid {}", key, v)))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
@@ -34,6 +41,10 @@ async fn main() -> std::io::Result<()> {
     let pg_spec = std::env::var("DATABASE_URL").expect("DATABASE_URL");
     let pg_mgr = ConnectionManager::<PgConnection>::new(pg_spec);
     let pg_pool = Data::new(r2d2::Pool::builder()
+        .max_size(env_or("DATABASE_POOL_SIZE", 10))
+        .connection_timeout(Duration::seconds(env_or("DATABASE_CONNECTION_TIMEOUT_SECS", 30))
+            .to_std()
+            .expect("DATABASE_CONNECTION_TIMEOUT_SECS can't be negative"))
         .build(pg_mgr)
         .expect("Failed to create pool."));
 
@@ -45,13 +56,29 @@ async fn main() -> std::io::Result<()> {
         .expect("Couldn't construct ElasticSearch transport");
     let es = Data::new(Elasticsearch::new(es_tp));
 
-    action::create_index(&es.clone().into_inner())
+    let rebuild = action::create_index(
+        &es.clone().into_inner(),
+        env_or("INDEX_MAPPING_DRIFT", MappingDrift::Log))
         .await
         .expect("Couldn't create index");
 
+    let contexts = Data::new(SearchContexts::new(
+        Duration::seconds(env_or("SEARCH_PIT_KEEP_ALIVE_SECS", 60)),
+        env_or("SEARCH_PIT_MAX_OPEN", 200)));
+
     let indexer = dmn::indexer::IndexDaemon::start(pg_pool.clone(), es.clone(), Duration::seconds(10));
 
-    let deleter = dmn::deleter::DeleteDaemon::start(pg_pool.clone(), Duration::seconds(30));
+    let reindexer = dmn::reindexer::ReindexDaemon::start(pg_pool.clone(), es.clone(), Duration::seconds(1));
+
+    if let Some(index) = rebuild {
+        reindexer.lock().unwrap().begin(index)
+            .expect("Couldn't start rebuilding index");
+    }
+
+    let deleter = dmn::deleter::DeleteDaemon::start(
+        pg_pool.clone(),
+        Duration::seconds(30),
+        Duration::seconds(env_or("DELETE_RETENTION_SECS", 24 * 60 * 60)));
 
     let bind = "127.0.0.1:8080";
 
@@ -61,16 +88,32 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(pg_pool.clone())
             .app_data(es.clone())
+            .app_data(contexts.clone())
             .app_data(indexer.clone())
+            .app_data(reindexer.clone())
             .app_data(deleter.clone())
+            .app_data(api::json_config().limit(4 * 1024 * 1024))
+            .app_data(api::query_config())
             .wrap(middleware::Logger::default())
             .service(api::health)
             .service(scope("/catalog")
                 .service(api::post_movie)
+                .service(api::post_movies)
+                .service(api::mget_movies)
+                .service(api::suggest_movies)
                 .service(api::get_movie)
                 .service(api::put_movie)
+                .service(api::patch_movie)
                 .service(api::delete_movie)
+                .service(api::restore_movie)
+                .service(api::get_related_movies)
                 .service(api::get_movies)
+                .service(api::admin::get_reindex)
+                .service(api::admin::post_reindex)
+                .service(api::admin::delete_stray_index)
+                .service(api::admin::get_index_failures)
+                .service(api::admin::post_retry_index_failures)
+                .service(api::admin::post_retry_index_failure)
             )
     })
     .bind(&bind)?